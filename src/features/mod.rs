@@ -5,8 +5,10 @@ pub mod shell;
 
 use raw_cpuid::CpuId;
 use scheduler::RUNNING_TASK;
+use tasks::TaskStatus;
 use x86_64;
 use x86_64::instructions::{port, rdtsc};
+use x86_64::registers::flags::{flags, Flags};
 
 /// Global variable to store the cpu frequency.
 static mut CPU_FREQ: u64 = 0;
@@ -167,25 +169,60 @@ pub fn get_cpu_freq() -> u64 {
 }
 
 /// The function calculates how many tsc ticks the current process has to sleep, dependent on the
-/// given time in milliseconds. After this the function saves the `sleep_ticks` in the `RUNNING_TASK`
-/// struct. To prevent CPU waste, the timer interrupt is called and thus the scheduler is called.
+/// given time in milliseconds. After this the task sleeps until this timestamp (see
+/// `sleep_until()`).
+///
+/// The wake up time is relative to the call, so periodic tasks should use `wait_next_period()`.
+/// Huge times saturate, the task then sleeps forever.
 pub fn msleep(ms: u64) {
-    trace_info!();
-    let one_sec = get_cpu_freq();
+    sleep_until((rdtsc() as usize).saturating_add(ms_to_ticks(ms)));
+}
 
-    let time = one_sec * ms / 1000; // (one_sec * ms / 1000) as i64; doesnt work!
-    let tsc = time + rdtsc();
-    trace_debug!("sleep until: {}", tsc);
+/// The function saves the `sleep_ticks` in the `RUNNING_TASK` struct and marks the task as
+/// `SLEEPING`. To prevent CPU waste, the timer interrupt is called and thus the scheduler is
+/// called. When the task wakes up, a new job is released at `tsc`. If `tsc` is already reached,
+/// the task is woken up on the next call of the scheduler.
+///
+/// # Arguments
+/// * `tsc` - (usize) Absolute timestamp in tsc ticks.
+pub fn sleep_until(tsc: usize) {
     unsafe {
         {
             x86_64::instructions::interrupts::disable();
-            RUNNING_TASK.lock().sleep_ticks = tsc as usize;
+            let mut running = RUNNING_TASK.lock();
+            running.sleep_ticks = tsc;
+            if running.status != TaskStatus::IDLE {
+                running.status = TaskStatus::SLEEPING;
+            }
             x86_64::instructions::interrupts::enable();
         }
         int!(0x20);
     }
 }
 
+/// Sleeps until the next period of the running task begins. The next job is released exactly one
+/// period after the release of the current job, independent of the execution time, so periodic
+/// tasks do not drift. If the current job took longer than its period, the next job is released
+/// immediately.
+pub fn wait_next_period() {
+    let (release_time, period) = without_interrupts(|| unsafe {
+        let running = RUNNING_TASK.lock();
+        (running.release_time, running.period)
+    });
+    assert!(
+        period != 0,
+        "wait_next_period() called by an aperiodic task"
+    );
+    sleep_until(release_time.saturating_add(period));
+}
+
+/// Converts milliseconds into tsc ticks, based on the cpu frequency. Saturates instead of
+/// overflowing, so huge timeouts mean *forever*.
+pub fn ms_to_ticks(ms: u64) -> usize {
+    let one_sec = get_cpu_freq();
+    (one_sec.saturating_mul(ms) / 1000) as usize // (one_sec * ms / 1000) as i64; doesnt work!
+}
+
 /// This sleep is not calling the scheduler.
 /// It is used for early sleeps, before any tasks oder scheduler are running.
 pub fn active_sleep(ms: u64) {
@@ -223,6 +260,26 @@ pub fn test_bit(byte: u8, bit: u8) -> bool {
     byte & bit > 0
 }
 
+/// Runs `f` with disabled interrupts. Afterwards the interrupts are only enabled again if they were
+/// enabled before, so the function can also be used during the initialization or inside the
+/// scheduler.
+pub fn without_interrupts<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let enabled = flags().contains(Flags::IF);
+    unsafe {
+        x86_64::instructions::interrupts::disable();
+    }
+    let result = f();
+    if enabled {
+        unsafe {
+            x86_64::instructions::interrupts::enable();
+        }
+    }
+    result
+}
+
 /// Disables the cursor on the vga_buffer.
 /// More information at [OSDev Wiki](https://wiki.osdev.org/Text_Mode_Cursor#Disabling_the_Cursor).
 pub fn disable_cursor() {
@@ -5,9 +5,18 @@
 //!     1. "help"     -> Displays a man page which shows a list of supported commands.
 //!     2. "tetris"   -> Starts a self developed version of the ancient tetris
 //!     3. "clock"    -> Adds a temporary clock which counts up to a specific amount of seconds
-//!     4. "reboot"   -> Reboots the system
-//!     5. "shutdown" -> Shuts down the system
-//!     6. "strg + c" -> Terminates the current running task which was issued from the shell
+//!     4. "stack"    -> Shows the maximum stack depth of all tasks
+//!     5. "heap"     -> Shows the heap usage of the system and of all tasks
+//!     6. "reboot"   -> Reboots the system
+//!     7. "shutdown" -> Shuts down the system
+//!     8. "strg + c" -> Terminates the current running task which was issued from the shell
+//!     9. "isolated" -> Starts a counter as an isolated task in its own address space
+//!    10. "user"     -> Starts a counter and a printer as user tasks in ring 3, which communicate
+//!                      over a queue
+//!    11. "timer"    -> Blinks a marker with software timers
+//!    12. "kill"     -> Kills the last started clock or timer demo
+//!    13. "overrun"  -> Shows the overrun policies of tasks which miss their deadline
+//!    14. "inversion" -> Shows the protocols against priority inversion of the kernel mutex
 //!
 //! A new Shell can be initialized with a custom number of lines, which is determined by passing
 //! the initial cursor position (altogether 25 rows are available on the screen).
@@ -18,11 +27,16 @@
 use alloc::string::String;
 use alloc::{string::ToString, Vec};
 use features::{reboot, shutdown};
-use tasks::{tetris, uptime_temp, NEW_TASKS, PIECE, TASK_STARTED};
+use syscall::user;
+use tasks::{
+    inversion_demo, isolated_counter, overrun_demo, spawn, stack_usage, tetris, timer_demo,
+    uptime_temp, TaskEntry, TaskHandle, TaskParams, DEFAULT_PRIORITY, DEFAULT_STACK_PAGES, PIECE,
+    TASK_STARTED,
+};
 #[allow(unused_imports)]
 use trace::*;
 use vga_buffer::*;
-use x86_64::VirtualAddress;
+use HEAP_ALLOCATOR;
 
 pub struct Shell {
     /// Specifies the initial cursor position (row, col).
@@ -39,6 +53,9 @@ pub struct Shell {
     parse_ctrl_command: bool,
     /// Contains the running task (started by the shell) as string.
     running_task: String,
+    /// Handle of the last clock or timer demo, the target of *kill*. The isolated counter is not
+    /// killed, because its unit of the semaphore would not be returned.
+    last_task: Option<TaskHandle>,
     /// Defines the screen area to which the content of tasks started by the shell is displayed.
     active_screen: (u8, u8, u8, u8),
     /// Text, which is displayed when the user issues an unsupported command.
@@ -61,6 +78,7 @@ impl Shell {
             parse_ctrl_command: false,
             active_screen: (30, 80, 0, 20),
             running_task: "".to_string(),
+            last_task: None,
             unkown_command_help:
                 "Unknown command. To see a list of possible commands, type `help`!".to_string(),
         }
@@ -225,18 +243,17 @@ impl Shell {
     }
 
     /// Called by `parse_input()`.
-    /// If neccessary (e.g. in case of tetris, clock), the function pointer of the task which should
-    /// be started is pushed to the vector NEW_TASKS, which stores new tasksto be started by the shell or other tasks.
-    /// The main task starts new task from this vector.
+    /// If neccessary (e.g. in case of tetris, clock), the task which should be started is passed to
+    /// `tasks::spawn()`, which pushes it to the vector NEW_TASKS. The main task starts new task from
+    /// this vector. The handle of a clock or timer demo is kept for *kill*, the other handles are
+    /// dropped, so the tasks run detached.
     /// In case of *reboot* or *shutdown* the corresponding function in the *features* crate is called.
     /// If an unsupported command is issued, an appropriate warning is displayed.
     fn parse_command(&mut self) {
         let x = self.input.to_string();
         self.input_history.push(x.clone());
         if x == "tetris" {
-            {
-                NEW_TASKS.lock().insert(0, VirtualAddress(tetris as usize));
-            }
+            spawn_shell_task('t', tetris, TaskParams::aperiodic(DEFAULT_PRIORITY));
             unsafe {
                 TASK_STARTED = true;
             }
@@ -249,20 +266,63 @@ impl Shell {
                 TASK_STARTED = true;
             }
             self.running_task = "help".to_string();
-        } else if x == "clock" {
-            {
-                NEW_TASKS
-                    .lock()
-                    .insert(0, VirtualAddress(uptime_temp as usize));
+        } else if x == "stack" {
+            self.show_stack_usage();
+            unsafe {
+                TASK_STARTED = true;
             }
-            if self.current_cursor_position.0 as usize >= BUFFER_HEIGHT - 1 {
-                self.print_shift_history();
-            } else {
-                self.current_cursor_position.0 += 1;
+            self.running_task = "stack".to_string();
+        } else if x == "heap" {
+            self.show_heap_usage();
+            unsafe {
+                TASK_STARTED = true;
             }
-            self.current_cursor_position.1 = self.default_cursor_position.1;
-            let cursor_position_height = self.current_cursor_position.0;
-            self.print_prompt(cursor_position_height, 0);
+            self.running_task = "heap".to_string();
+        } else if x == "clock" {
+            self.last_task = Some(spawn_shell_task(
+                'u',
+                uptime_temp,
+                TaskParams::aperiodic(DEFAULT_PRIORITY),
+            ));
+            self.next_prompt_line();
+        } else if x == "isolated" {
+            spawn_shell_task(
+                'i',
+                isolated_counter,
+                TaskParams::aperiodic(DEFAULT_PRIORITY).isolated(),
+            );
+            self.next_prompt_line();
+        } else if x == "user" {
+            spawn_shell_task(
+                'r',
+                user::counter,
+                TaskParams::aperiodic(DEFAULT_PRIORITY).user(),
+            );
+            spawn_shell_task(
+                'p',
+                user::printer,
+                TaskParams::aperiodic(DEFAULT_PRIORITY).user(),
+            );
+            self.next_prompt_line();
+        } else if x == "timer" {
+            self.last_task = Some(spawn_shell_task(
+                'b',
+                timer_demo,
+                TaskParams::aperiodic(DEFAULT_PRIORITY),
+            ));
+            self.next_prompt_line();
+        } else if x == "overrun" {
+            spawn_shell_task('d', overrun_demo, TaskParams::aperiodic(DEFAULT_PRIORITY));
+            self.next_prompt_line();
+        } else if x == "inversion" {
+            spawn_shell_task('v', inversion_demo, TaskParams::aperiodic(DEFAULT_PRIORITY));
+            self.next_prompt_line();
+        } else if x == "kill" {
+            self.kill_last_task();
+            unsafe {
+                TASK_STARTED = true;
+            }
+            self.running_task = "kill".to_string();
         } else if x == "" {
             ;
         } else if x == "reboot" {
@@ -339,75 +399,217 @@ impl Shell {
             Color::Black,
         );
         write_at_background(
-            "1. help     > Shows a full list of possible",
+            "1. help     > Shows all shell commands",
             2,
             35,
             Color::White,
             Color::Black,
         );
         write_at_background(
-            "              shell commands",
+            "2. tetris   > Starts a funky tetris game",
             3,
             35,
             Color::White,
             Color::Black,
         );
         write_at_background(
-            "2. tetris   > Starts a funky tetris game",
+            "3. clock    > Adds a temporary clock to the",
+            4,
+            35,
+            Color::White,
+            Color::Black,
+        );
+        write_at_background(
+            "              left of the screen",
             5,
             35,
             Color::White,
             Color::Black,
         );
         write_at_background(
-            "3. clock    > Adds a temporary clock to the",
+            "4. stack    > Shows the maximum stack depth",
+            6,
+            35,
+            Color::White,
+            Color::Black,
+        );
+        write_at_background(
+            "              of all tasks",
             7,
             35,
             Color::White,
             Color::Black,
         );
         write_at_background(
-            "              left of the screen",
+            "5. heap     > Shows the heap usage",
             8,
             35,
             Color::White,
             Color::Black,
         );
         write_at_background(
-            "4. reboot   > Reboots the system",
+            "6. reboot   > Reboots the system",
+            9,
+            35,
+            Color::White,
+            Color::Black,
+        );
+        write_at_background(
+            "7. shutdown > Powers off the system",
             10,
             35,
             Color::White,
             Color::Black,
         );
         write_at_background(
-            "5. shutdown > Powers off the system",
+            "8. ctrl-c   > Cancels the last shell command",
+            11,
+            35,
+            Color::White,
+            Color::Black,
+        );
+        write_at_background(
+            "9. isolated > Starts a counter in its own",
             12,
             35,
             Color::White,
             Color::Black,
         );
         write_at_background(
-            "6. ctrl-c   > Cancels the last command issued",
+            "              address space",
+            13,
+            35,
+            Color::White,
+            Color::Black,
+        );
+        write_at_background(
+            "10. user    > Counts with two ring 3 tasks",
             14,
             35,
             Color::White,
             Color::Black,
         );
         write_at_background(
-            "              from the shell and activates",
+            "11. timer   > Blinks a marker with timers",
             15,
             35,
             Color::White,
             Color::Black,
         );
         write_at_background(
-            "              new input",
+            "12. kill    > Kills the last clock or timer",
             16,
             35,
             Color::White,
             Color::Black,
         );
+        write_at_background(
+            "13. overrun > Misses deadlines on purpose",
+            17,
+            35,
+            Color::White,
+            Color::Black,
+        );
+        write_at_background(
+            "14. inversion > Shows the mutex protocols",
+            18,
+            35,
+            Color::White,
+            Color::Black,
+        );
+    }
+
+    /// Prints the maximum stack depth of all tasks to the active screen area, one task per row.
+    /// The values are measured once, when the command is issued.
+    fn show_stack_usage(&mut self) {
+        write_at_background(
+            "###### STACK USAGE (max/size) ######",
+            0,
+            35,
+            Color::White,
+            Color::Black,
+        );
+        for (i, usage) in stack_usage()
+            .iter()
+            .take((self.active_screen.3 - 2) as usize)
+            .enumerate()
+        {
+            // more than three quarters of the stack were used
+            let color = if usage.used * 4 >= usage.size * 3 {
+                Color::Red
+            } else {
+                Color::White
+            };
+            let text = format!(
+                "Task {} ({:3}): {:5} / {:5} B ({:3}%)",
+                usage.name,
+                usage.pid,
+                usage.used,
+                usage.size,
+                usage.used * 100 / usage.size
+            );
+            write_at_background(&text, i as u8 + 2, 35, color, Color::Black);
+        }
+    }
+
+    /// Prints the heap usage of the system and of all tasks to the active screen area, one task
+    /// per row. The values are measured once, when the command is issued.
+    fn show_heap_usage(&mut self) {
+        write_at_background(
+            "###### HEAP USAGE ######",
+            0,
+            35,
+            Color::White,
+            Color::Black,
+        );
+        let stats = HEAP_ALLOCATOR.stats();
+        let text = format!(
+            "Used: {} / {} B (peak {})",
+            stats.used, stats.size, stats.peak
+        );
+        write_at_background(&text, 2, 35, Color::White, Color::Black);
+        let text = format!("Max size: {} B", stats.max_size);
+        write_at_background(&text, 3, 35, Color::White, Color::Black);
+        let text = format!(
+            "Allocs: {}, frees: {}, failed: {}",
+            stats.allocations, stats.frees, stats.failures
+        );
+        let color = if stats.failures > 0 {
+            Color::Red
+        } else {
+            Color::White
+        };
+        write_at_background(&text, 4, 35, color, Color::Black);
+
+        for (i, task) in HEAP_ALLOCATOR
+            .task_stats()
+            .iter()
+            .take((self.active_screen.3 - 6) as usize)
+            .enumerate()
+        {
+            let text = format!(
+                "Task {:3}: {:6} B (peak {:6}, {:5} allocs)",
+                task.pid, task.used, task.peak, task.allocations
+            );
+            write_at_background(&text, i as u8 + 6, 35, Color::White, Color::Black);
+        }
+    }
+
+    /// Kills the last clock or timer demo and prints the result to the active screen area. A task
+    /// which already ended is only reported. The shell does not wait for the end of the task, the
+    /// handle is dropped, so the scheduler removes it.
+    fn kill_last_task(&mut self) {
+        let text = match self.last_task.take() {
+            Some(handle) => match handle.try_join() {
+                Some(code) => format!("Task {} exited with code {}", handle.pid(), code),
+                None => {
+                    handle.kill();
+                    format!("Task {} killed", handle.pid())
+                }
+            },
+            None => "No task to kill".to_string(),
+        };
+        write_at_background(&text, 0, 35, Color::White, Color::Black);
     }
 
     /// Called by `parse_input()` after *ctrl* and another key was pressed.
@@ -419,7 +621,11 @@ impl Shell {
     fn parse_ctrl_command(&mut self, input: String) {
         if input == "c" {
             if unsafe { TASK_STARTED } {
-                if self.running_task == "help" {
+                if self.running_task == "help"
+                    || self.running_task == "stack"
+                    || self.running_task == "heap"
+                    || self.running_task == "kill"
+                {
                     self.reset_shell();
                 } else {
                     self.terminate_running_task = true;
@@ -438,6 +644,15 @@ impl Shell {
     pub fn reset_shell(&mut self) {
         self.terminate_running_task = false;
         self.clear_active_screen();
+        self.next_prompt_line();
+        unsafe {
+            TASK_STARTED = false;
+        }
+    }
+
+    /// Moves the cursor to the start of the next line and prints a prompt there. If the last line
+    /// is reached, the previous inputs are shifted up instead (see `print_shift_history()`).
+    fn next_prompt_line(&mut self) {
         if self.current_cursor_position.0 as usize >= BUFFER_HEIGHT - 1 {
             self.print_shift_history();
         } else {
@@ -446,9 +661,6 @@ impl Shell {
         self.current_cursor_position.1 = self.default_cursor_position.1;
         let cursor_position_height = self.current_cursor_position.0;
         self.print_prompt(cursor_position_height, 0);
-        unsafe {
-            TASK_STARTED = false;
-        }
     }
 
     /// Called by several shell functions to shift the previous inputs up if the last line of the shell
@@ -484,3 +696,16 @@ impl Shell {
         self.print_prompt(BUFFER_HEIGHT as u8 - 1, 0);
     }
 }
+
+/// Starts the task of a shell command with the default stack size.
+///
+/// # Arguments
+/// * `name` - (char) Name of the task.
+/// * `entry` - (TaskEntry) Function of the task.
+/// * `params` - (TaskParams) Parameters of the task, must be aperiodic.
+///
+/// # Return
+/// * `TaskHandle` - Handle of the task, dropping it detaches the task.
+fn spawn_shell_task(name: char, entry: TaskEntry, params: TaskParams) -> TaskHandle {
+    spawn(name, entry, 0, DEFAULT_STACK_PAGES, params).expect("aperiodic tasks are always admitted")
+}
@@ -17,15 +17,23 @@ impl Gdt {
     }
 
     pub fn add_entry(&mut self, entry: Descriptor) -> SegmentSelector {
-        let index = match entry {
-            Descriptor::UserSegment(value) => self.push(value),
+        let (index, privilege_level) = match entry {
+            Descriptor::UserSegment(value) => {
+                // the selector of a ring 3 segment needs the same privilege level
+                let privilege_level = if value & DPL_RING_3.bits() == DPL_RING_3.bits() {
+                    PrivilegeLevel::Ring3
+                } else {
+                    PrivilegeLevel::Ring0
+                };
+                (self.push(value), privilege_level)
+            }
             Descriptor::SystemSegment(value_low, value_high) => {
                 let index = self.push(value_low);
                 self.push(value_high);
-                index
+                (index, PrivilegeLevel::Ring0)
             }
         };
-        SegmentSelector::new(index as u16, PrivilegeLevel::Ring0)
+        SegmentSelector::new(index as u16, privilege_level)
     }
 
     fn push(&mut self, value: u64) -> usize {
@@ -63,6 +71,18 @@ impl Descriptor {
         Descriptor::UserSegment(flags.bits())
     }
 
+    /// Code segment for tasks which run in ring 3.
+    pub fn user_code_segment() -> Descriptor {
+        let flags = USER_SEGMENT | PRESENT | EXECUTABLE | LONG_MODE | DPL_RING_3;
+        Descriptor::UserSegment(flags.bits())
+    }
+
+    /// Data segment for tasks which run in ring 3, used as stack segment.
+    pub fn user_data_segment() -> Descriptor {
+        let flags = USER_SEGMENT | PRESENT | WRITABLE | DPL_RING_3;
+        Descriptor::UserSegment(flags.bits())
+    }
+
     pub fn tss_segment(tss: &'static TaskStateSegment) -> Descriptor {
         use bit_field::BitField;
         use core::mem::size_of;
@@ -87,9 +107,11 @@ impl Descriptor {
 
 bitflags! {
     struct DescriptorFlags: u64 {
+        const WRITABLE          = 1 << 41;
         const CONFORMING        = 1 << 42;
         const EXECUTABLE        = 1 << 43;
         const USER_SEGMENT      = 1 << 44;
+        const DPL_RING_3        = 3 << 45;
         const PRESENT           = 1 << 47;
         const LONG_MODE         = 1 << 53;
     }
@@ -1,15 +1,33 @@
 //! This module handles all interrupts. Some parts are taken from the blog-os by Phil Oppermann.
 //! Except the timer and keyboard interrupt, all interrupts are printing the error on the screen
 //! and will then reboot the system after 5 seconds.
+//!
+//! A page fault in the guard page of the running task is a stack overflow. Only this task is
+//! killed, the system keeps running. The page fault handler has its own stack, because the stack
+//! of the task is full.
+//!
+//! The timer interrupt has no `x86-interrupt` handler. Its entry is the naked function
+//! `timer_entry`, which saves all registers of the interrupted task, so the scheduler can switch
+//! to another task by exchanging the stack pointer.
+//!
+//! User tasks call the kernel with `int 0x80` (see `syscall`). It is the only interrupt which may
+//! be triggered in ring 3, its entry `syscall_entry` saves the registers like the timer entry. A
+//! user task which causes an exception is killed.
+use core::intrinsics;
+use core::mem;
 use features::{active_sleep, reboot};
-use memory::MemoryController;
+use memory::{self, MemoryController};
 use pic::ChainedPics;
-use scheduler::schedule;
+use scheduler::context;
+use scheduler::{schedule, RUNNING_TASK};
 use spin::{Mutex, Once};
+use syscall;
+use tasks::{self, KILLED};
 use x86_64;
 use x86_64::structures::idt::{ExceptionStackFrame, Idt, PageFaultErrorCode};
 use x86_64::structures::tss::TaskStateSegment;
-use x86_64::VirtualAddress;
+use x86_64::{PrivilegeLevel, VirtualAddress};
+use HEAP_ALLOCATOR;
 
 mod gdt;
 
@@ -33,7 +51,11 @@ lazy_static! {
         idt.stack_segment_fault.set_handler_fn(stack_segment_fault);
         idt.general_protection_fault
             .set_handler_fn(general_protection_fault);
-        idt.page_fault.set_handler_fn(page_fault);
+        unsafe {
+            idt.page_fault
+                .set_handler_fn(page_fault)
+                .set_stack_index(PAGE_FAULT_IST_INDEX as u16);
+        }
         idt.x87_floating_point.set_handler_fn(x87_floating_point);
 
         idt.virtualization.set_handler_fn(virtualization);
@@ -42,11 +64,18 @@ lazy_static! {
         idt.machine_check.set_handler_fn(machine_check);
         idt.alignment_check.set_handler_fn(alignment_check);
 
-        idt.interrupts[0].set_handler_fn(timer_handler);
+        let timer: extern "x86-interrupt" fn(&mut ExceptionStackFrame) =
+            unsafe { mem::transmute(timer_entry as extern "C" fn()) };
+        idt.interrupts[0].set_handler_fn(timer);
         idt.interrupts[1].set_handler_fn(keyboard_handler);
         idt.interrupts[2].set_handler_fn(handler_2);
         idt.interrupts[3].set_handler_fn(handler_3);
         idt.interrupts[4].set_handler_fn(handler_4);
+        let syscall: extern "x86-interrupt" fn(&mut ExceptionStackFrame) =
+            unsafe { mem::transmute(syscall_entry as extern "C" fn()) };
+        idt.interrupts[SYSCALL_INTERRUPT - 0x20]
+            .set_handler_fn(syscall)
+            .set_privilege_level(PrivilegeLevel::Ring3);
         unsafe {
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
@@ -64,9 +93,18 @@ static TSS: Once<TaskStateSegment> = Once::new();
 /// Variable to store the global description table
 static GDT: Once<gdt::Gdt> = Once::new();
 
+/// Selectors of the code and the data segment of user tasks, set by `init()`.
+static USER_SEGMENTS: Once<(u16, u16)> = Once::new();
+
 /// Code of the `blog-os by phil oppermann`
 const DOUBLE_FAULT_IST_INDEX: usize = 0;
 
+/// Index of the stack of the page fault handler in the interrupt stack table.
+const PAGE_FAULT_IST_INDEX: usize = 1;
+
+/// Interrupt of the syscalls (`int 0x80`).
+const SYSCALL_INTERRUPT: usize = 0x80;
+
 /// Stores PICS to handle interrupts.
 /// Interrupts need to be remapped for the PICS.
 static PICS: Mutex<ChainedPics> = Mutex::new(unsafe { ChainedPics::new(0x20, 0xA0) });
@@ -80,23 +118,35 @@ pub fn init(memory_controller: &mut MemoryController) {
     let double_fault_stack = memory_controller
         .alloc_stack(1)
         .expect("could not allocate double fault stack");
+    let page_fault_stack = memory_controller
+        .alloc_stack(2)
+        .expect("could not allocate page fault stack");
+    // the exceptions can happen in every address space
+    memory_controller.share_stack(&double_fault_stack);
+    memory_controller.share_stack(&page_fault_stack);
 
     let tss = TSS.call_once(|| {
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX] =
             VirtualAddress(double_fault_stack.top());
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX] = VirtualAddress(page_fault_stack.top());
         tss
     });
 
     let mut code_selector = SegmentSelector(0);
     let mut tss_selector = SegmentSelector(0);
+    let mut user_code_selector = SegmentSelector(0);
+    let mut user_data_selector = SegmentSelector(0);
     let gdt = GDT.call_once(|| {
         let mut gdt = gdt::Gdt::new();
         code_selector = gdt.add_entry(gdt::Descriptor::kernel_code_segment());
         tss_selector = gdt.add_entry(gdt::Descriptor::tss_segment(&tss));
+        user_code_selector = gdt.add_entry(gdt::Descriptor::user_code_segment());
+        user_data_selector = gdt.add_entry(gdt::Descriptor::user_data_segment());
         gdt
     });
     gdt.load();
+    USER_SEGMENTS.call_once(|| (user_code_selector.0, user_data_selector.0));
 
     unsafe {
         // reload code segment register
@@ -110,6 +160,29 @@ pub fn init(memory_controller: &mut MemoryController) {
     IDT.load();
 }
 
+/// Returns the selectors of the code and the stack segment of user tasks, with privilege level 3.
+///
+/// # Return
+/// * `(u64, u64)` - The code segment and the stack segment.
+pub fn user_segments() -> (u64, u64) {
+    let &(code, data) = USER_SEGMENTS.try().expect("gdt is not initialized");
+    (code as u64, data as u64)
+}
+
+/// Sets the stack which the cpu uses when a user task is interrupted or calls a syscall (`rsp0`
+/// of the TSS). Called by the scheduler before a user task continues.
+///
+/// # Arguments
+/// * `top` - (usize) Top address of the kernel stack of the task.
+pub fn set_kernel_stack(top: usize) {
+    let tss = TSS.try().expect("TSS is not initialized");
+    // the TSS is only read by the cpu, so it can be changed while it is loaded
+    unsafe {
+        let tss = tss as *const TaskStateSegment as *mut TaskStateSegment;
+        (*tss).privilege_stack_table[0] = VirtualAddress(top);
+    }
+}
+
 /// This will initialize a timer which will cause an interrupt.
 /// Currently this is needed to be done with inline assembly because there is no way to realize it with *normal*
 /// rust code.
@@ -145,6 +218,7 @@ pub fn init_timer() {
 }
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut ExceptionStackFrame) {
+    kill_user_task(stack_frame, "breakpoint");
     println!("KRASSE EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
     unsafe {
         PICS.lock().notify_end_of_interrupt(0x03 as u8);
@@ -160,11 +234,13 @@ extern "x86-interrupt" fn double_fault_handler(
 }
 
 extern "x86-interrupt" fn divide_by_zero(stack_frame: &mut ExceptionStackFrame) {
+    kill_user_task(stack_frame, "divide_by_zero");
     println!("EXCEPTION: divide_by_zero\n{:#?}", stack_frame);
     fault_reboot();
 }
 
 extern "x86-interrupt" fn debug(stack_frame: &mut ExceptionStackFrame) {
+    kill_user_task(stack_frame, "debug");
     println!("EXCEPTION: debug\n{:#?}", stack_frame);
     fault_reboot();
 }
@@ -175,21 +251,25 @@ extern "x86-interrupt" fn non_maskable_interrupt(stack_frame: &mut ExceptionStac
 }
 
 extern "x86-interrupt" fn overflow(stack_frame: &mut ExceptionStackFrame) {
+    kill_user_task(stack_frame, "overflow");
     println!("EXCEPTION: overflow\n{:#?}", stack_frame);
     fault_reboot();
 }
 
 extern "x86-interrupt" fn bound_range_exceeded(stack_frame: &mut ExceptionStackFrame) {
+    kill_user_task(stack_frame, "bound_range_exceeded");
     println!("EXCEPTION: bound_range_exceeded\n{:#?}", stack_frame);
     fault_reboot();
 }
 
 extern "x86-interrupt" fn invalid_opcode(stack_frame: &mut ExceptionStackFrame) {
+    kill_user_task(stack_frame, "invalid_opcode");
     println!("EXCEPTION: invalid_opcode\n{:#?}", stack_frame);
     fault_reboot();
 }
 
 extern "x86-interrupt" fn device_not_available(stack_frame: &mut ExceptionStackFrame) {
+    kill_user_task(stack_frame, "device_not_available");
     println!("EXCEPTION: device_not_available\n{:#?}", stack_frame);
     fault_reboot();
 }
@@ -203,6 +283,7 @@ extern "x86-interrupt" fn segment_not_present(
     stack_frame: &mut ExceptionStackFrame,
     _error_code: u64,
 ) {
+    kill_user_task(stack_frame, "segment_not_present");
     println!("EXCEPTION: segment_not_present\n{:#?}", stack_frame);
     fault_reboot();
 }
@@ -211,6 +292,7 @@ extern "x86-interrupt" fn stack_segment_fault(
     stack_frame: &mut ExceptionStackFrame,
     _error_code: u64,
 ) {
+    kill_user_task(stack_frame, "stack_segment_fault");
     println!("EXCEPTION: stack_segment_fault\n{:#?}", stack_frame);
     fault_reboot();
 }
@@ -219,19 +301,115 @@ extern "x86-interrupt" fn general_protection_fault(
     stack_frame: &mut ExceptionStackFrame,
     _error_code: u64,
 ) {
+    kill_user_task(stack_frame, "general_protection_fault");
     println!("EXCEPTION: general_protection_fault\n{:#?}", stack_frame);
     fault_reboot();
 }
 
+/// Handles page faults on its own stack. If the faulting address is in the guard page of the
+/// running task, the task overflowed its stack and is killed. An isolated task is killed on every
+/// page fault, e.g. if it accesses the heap or another stack, which are not mapped in its address
+/// space. The scheduler releases the resources of the killed task. Every other page fault reboots
+/// the system.
+///
+/// The fault is reported with `println!`, which does not use the heap. The fault may have happened
+/// inside the heap allocator, then its lock is held and the task can't be killed, because the
+/// scheduler needs the heap.
 extern "x86-interrupt" fn page_fault(
     stack_frame: &mut ExceptionStackFrame,
-    _page_error_struct: PageFaultErrorCode,
+    page_error_struct: PageFaultErrorCode,
 ) {
-    println!("EXCEPTION: page_fault\n{:#?}", stack_frame);
+    let address = fault_address();
+    // the scheduler needs the heap, even if the interrupted task forbids it or is isolated
+    memory::enter_kernel_space();
+    memory::set_heap_forbidden(false);
+    let fault = unsafe {
+        // the lock is held if the fault happened inside the scheduler
+        RUNNING_TASK
+            .try_lock()
+            .and_then(|running| match running.stack {
+                Some(ref stack) if stack.is_guard_page(address) => Some(TaskFault::Overflow(
+                    running.name,
+                    running.pid,
+                    stack.bottom(),
+                    stack.top(),
+                )),
+                _ if running.address_space.is_some() => {
+                    Some(TaskFault::Isolation(running.name, running.pid))
+                }
+                _ => None,
+            })
+    };
+    if let Some(fault) = fault {
+        match fault {
+            TaskFault::Overflow(name, pid, bottom, top) => println!(
+                "stack overflow in task {} ({}), stack 0x{:x} - 0x{:x}, address 0x{:x}",
+                pid, name, bottom, top, address
+            ),
+            TaskFault::Isolation(name, pid) => println!(
+                "isolated task {} ({}) accessed 0x{:x}, which is not in its address space",
+                pid, name, address
+            ),
+        }
+        if !HEAP_ALLOCATOR.is_locked() {
+            // the scheduler saves the context of the task on the stack of this handler, it is
+            // never continued
+            tasks::exit(KILLED);
+        }
+        println!("the task holds the heap lock and can't be killed");
+    }
+    println!(
+        "EXCEPTION: page_fault at 0x{:x} ({:?})\n{:#?}",
+        address, page_error_struct, stack_frame
+    );
     fault_reboot();
 }
 
+/// Kills the running task if an exception happened in ring 3, so a user task can't crash the
+/// system. Returns if the exception happened in the kernel.
+///
+/// # Arguments
+/// * `stack_frame` - (&ExceptionStackFrame) The stack frame of the exception.
+/// * `exception` - (&str) Name of the exception, used for the trace.
+fn kill_user_task(stack_frame: &ExceptionStackFrame, exception: &str) {
+    if stack_frame.code_segment & 3 != PrivilegeLevel::Ring3 as u64 {
+        return;
+    }
+    memory::enter_kernel_space();
+    memory::set_heap_forbidden(false);
+    let (name, pid) = unsafe {
+        let running = RUNNING_TASK.lock();
+        (running.name, running.pid)
+    };
+    trace_error!(
+        "user task {} ({}) caused {} at 0x{:x}",
+        pid,
+        name,
+        exception,
+        stack_frame.instruction_pointer.0
+    );
+    tasks::exit(KILLED);
+}
+
+/// Page fault of a task which is killed, with the *name* and the pid of the task.
+enum TaskFault {
+    /// The task overflowed its stack, which is given by its bottom and top address.
+    Overflow(char, usize, usize, usize),
+    /// An isolated task accessed an address outside of its address space.
+    Isolation(char, usize),
+}
+
+/// Returns the address which caused the last page fault (`cr2` register).
+fn fault_address() -> usize {
+    let address: usize;
+    unsafe {
+        asm!("mov $0, cr2" : "=r"(address) ::: "intel");
+    }
+    address
+}
+
 extern "x86-interrupt" fn x87_floating_point(stack_frame: &mut ExceptionStackFrame) {
+    kill_user_task(stack_frame, "x87_floating_point");
     println!("EXCEPTION: x87_floating_point\n{:#?}", stack_frame);
     fault_reboot();
 }
@@ -249,6 +427,7 @@ extern "x86-interrupt" fn security_exception(
 }
 
 extern "x86-interrupt" fn simd_floating_point(stack_frame: &mut ExceptionStackFrame) {
+    kill_user_task(stack_frame, "simd_floating_point");
     println!("EXCEPTION: simd_floating_point\n{:#?}", stack_frame);
     fault_reboot();
 }
@@ -259,6 +438,7 @@ extern "x86-interrupt" fn machine_check(stack_frame: &mut ExceptionStackFrame) {
 }
 
 extern "x86-interrupt" fn alignment_check(stack_frame: &mut ExceptionStackFrame, _error_code: u64) {
+    kill_user_task(stack_frame, "alignment_check");
     println!("EXCEPTION: alignment_check\n{:#?}", stack_frame);
     fault_reboot();
 }
@@ -272,18 +452,94 @@ pub fn trigger_test_interrupt() {
     println!("Interrupt returned!");
 }
 
-/// Handles timer interrupts.
-/// All interrupts are disabled to prevent errors.
+/// Assembly of the entry stubs which can switch the task (`timer_entry` and `syscall_entry`).
+/// The cpu has already pushed the interrupt stack frame and disabled the interrupts. The stub
+/// pushes all general purpose registers and `cr3` onto the stack of the interrupted task and calls
+/// the handler with the resulting stack pointer (see `scheduler::context::Context`). The returned
+/// stack pointer may belong to another task. The registers are restored from this stack, `cr3` is
+/// only written if the address space changes, because this flushes the TLB. `iretq` continues the
+/// task, including its `rflags`.
+macro_rules! context_entry {
+    ($handler:ident) => {
+        unsafe {
+            asm!("
+                    push rax
+                    push rbx
+                    push rcx
+                    push rdx
+                    push rsi
+                    push rdi
+                    push rbp
+                    push r8
+                    push r9
+                    push r10
+                    push r11
+                    push r12
+                    push r13
+                    push r14
+                    push r15
+                    sub rsp, 8
+                    mov rax, cr3
+                    push rax
+
+                    mov rdi, rsp
+                    call $0
+                    mov rsp, rax
+
+                    pop rax
+                    add rsp, 8
+                    mov rcx, cr3
+                    cmp rax, rcx
+                    je 1f
+                    mov cr3, rax
+                1:
+                    pop r15
+                    pop r14
+                    pop r13
+                    pop r12
+                    pop r11
+                    pop r10
+                    pop r9
+                    pop r8
+                    pop rbp
+                    pop rdi
+                    pop rsi
+                    pop rdx
+                    pop rcx
+                    pop rbx
+                    pop rax
+                    iretq
+                "
+                :: "i"($handler as extern "C" fn(usize) -> usize)
+                :: "intel", "volatile");
+            intrinsics::unreachable();
+        }
+    };
+}
+
+/// Entry of the timer interrupt, also used for `int 0x20`. Calls `timer_handler`, see
+/// `context_entry!`.
+#[naked]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+extern "C" fn timer_entry() {
+    context_entry!(timer_handler);
+}
+
+/// Handles timer interrupts, called by `timer_entry` with disabled interrupts.
 /// First the scheduler is called to choose a new task,
 /// then the timer is resetted and the new timer interval is set to 10000 ticks (8.38 ms).
+/// The interrupts stay disabled, they are enabled again by `iretq` with the flags of the new task.
+///
+/// # Arguments
+/// * `stack_pointer` - (usize) Stack pointer of the interrupted task after all registers are saved.
+///
+/// # Return
+/// * `usize` - Stack pointer of the task which runs next.
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-extern "x86-interrupt" fn timer_handler(stack_frame: &mut ExceptionStackFrame) {
-    //println!("timer_handler");
-    unsafe {
-        x86_64::instructions::interrupts::disable();
-    }
-
-    schedule(stack_frame);
+extern "C" fn timer_handler(stack_pointer: usize) -> usize {
+    // the scheduler needs the heap, which is not mapped in the address spaces of isolated tasks
+    memory::enter_kernel_space();
+    let stack_pointer = schedule(VirtualAddress(stack_pointer));
 
     //reset timer
     unsafe {
@@ -305,8 +561,50 @@ extern "x86-interrupt" fn timer_handler(stack_frame: &mut ExceptionStackFrame) {
             let mut unwrapped = locked.expect("scheduler failed");
             unwrapped.notify_end_of_interrupt(0x20 as u8);
         }
-        x86_64::instructions::interrupts::enable();
     }
+    stack_pointer.0
+}
+
+/// Entry of the syscall interrupt (`int 0x80`), which is the only interrupt user tasks may
+/// trigger. Calls `syscall_handler`, see `context_entry!`.
+#[naked]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+extern "C" fn syscall_entry() {
+    context_entry!(syscall_handler);
+}
+
+/// Handles a syscall of a user task, called by `syscall_entry` with disabled interrupts on the
+/// kernel stack of the task. The number of the syscall is passed in `rax`, the arguments in `rdi`,
+/// `rsi` and `rdx`. The result is returned in `rax`, all other registers are preserved.
+///
+/// A syscall may block the task, the scheduler is then called from inside the syscall and the
+/// task continues here when it is scheduled again.
+///
+/// # Arguments
+/// * `stack_pointer` - (usize) Stack pointer of the calling task after all registers are saved.
+///
+/// # Return
+/// * `usize` - Stack pointer of the calling task.
+extern "C" fn syscall_handler(stack_pointer: usize) -> usize {
+    // the syscalls need the heap, which is not mapped in the address space of the task. The task
+    // allows the heap until the syscall returns, because the scheduler may run in between.
+    memory::enter_kernel_space();
+    set_heap_forbidden(false);
+    let context = unsafe { context::get_mut(VirtualAddress(stack_pointer)) };
+    context.rax = syscall::dispatch(
+        context.rax as usize,
+        context.rdi as usize,
+        context.rsi as usize,
+        context.rdx as usize,
+    ) as u64;
+    set_heap_forbidden(true);
+    stack_pointer
+}
+
+/// Forbids or allows the heap for the running task, until it is changed again.
+fn set_heap_forbidden(forbidden: bool) {
+    unsafe { RUNNING_TASK.lock().heap_forbidden = forbidden };
+    memory::set_heap_forbidden(forbidden);
 }
 
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
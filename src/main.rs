@@ -11,8 +11,9 @@
 //! They're hard to debug, because the `gdb` debugger causes other faults when trying to debug.
 //!
 #![feature(lang_items)]
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
 #![feature(const_fn)]
 #![feature(ptr_internals)]
 #![feature(asm)]
@@ -33,6 +34,8 @@ mod interrupts;
 mod memory;
 mod pic;
 mod scheduler;
+mod sync;
+mod syscall;
 mod tasks;
 
 extern crate volatile;
@@ -48,6 +51,7 @@ extern crate raw_cpuid;
 extern crate x86_64;
 #[macro_use]
 extern crate alloc;
+#[cfg(not(test))]
 extern crate rlibc;
 #[macro_use]
 extern crate once;
@@ -56,13 +60,16 @@ extern crate cpuio;
 extern crate linked_list_allocator;
 
 use alloc::string::{String, ToString};
+use alloc::Vec;
+use core::mem;
 use features::{active_sleep, disable_cursor, get_cpu_freq, msleep};
 use interrupts::fault_reboot;
+use memory::{AddressSpace, MemoryController, Stack};
 use os_bootinfo::BootInfo;
 use raw_cpuid::CpuId;
-use tasks::{tetris, uptime_temp};
 
 /// Used when a panic occurs. The function prints the file and the line on the screen if a panic happens.
+#[cfg(not(test))]
 #[lang = "panic_fmt"]
 #[no_mangle]
 pub extern "C" fn rust_begin_panic(
@@ -88,9 +95,12 @@ pub extern "C" fn rust_begin_panic(
 /// in the current version. Indeed it is possible to pass the `memory_controller` to a function, but
 /// it is not possible to use it in a new task.
 ///
-/// To start additional tasks in the running system, they must be added to the vector `NEW_TASKS`.
-/// The main task looks every 200ms for new tasks in the vector. If there is a new task, some memory
-/// is allocated and then the task is pushed to the TASKS vector, which is then used by the scheduler.
+/// To start additional tasks in the running system, they must be added to the vector `NEW_TASKS`
+/// with `tasks::spawn()`. Periodic tasks are only added if the task set stays schedulable. The main
+/// task looks every 200ms for new tasks in the vector. If there is a new task, some memory is
+/// allocated and then the task is pushed to the TASKS vector, which is then used by the scheduler.
+/// The stacks of finished tasks are freed at the same time (see `tasks::FINISHED_STACKS`).
+#[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
     boot_info
@@ -100,15 +110,14 @@ pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
     let mut memory_controller = memory::init(boot_info);
 
     unsafe {
-        HEAP_ALLOCATOR
-            .lock()
-            .init(HEAP_START, HEAP_START + HEAP_SIZE);
+        HEAP_ALLOCATOR.init(HEAP_START, HEAP_SIZE, HEAP_MAX_SIZE);
     }
 
     disable_cursor();
 
     // initialize our IDT
     interrupts::init(&mut memory_controller);
+    syscall::init(&mut memory_controller);
     unsafe {
         x86_64::instructions::interrupts::disable();
     }
@@ -116,6 +125,7 @@ pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
 
     let cpuid = CpuId::new();
 
+    scheduler::set_policy(SCHEDULING_POLICY);
     scheduler::sched_init(&mut memory_controller);
 
     let mut vendor_info = "".to_string();
@@ -141,49 +151,169 @@ pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
     trace_fatal!("Freq{:?}", cpuid!(1));
     trace_fatal!("System Info");
     trace_fatal!("Calculated CPU-frequency: {}", freq);
-    trace_fatal!("Heap Size: {}", HEAP_SIZE);
+    trace_fatal!("Heap Size: {} (max {})", HEAP_SIZE, HEAP_MAX_SIZE);
+    let heap = HEAP_ALLOCATOR.stats();
+    trace_fatal!(
+        "Heap: {} used, {} peak, {} allocations",
+        heap.used,
+        heap.peak,
+        heap.allocations
+    );
+    let frames = memory_controller.frame_stats();
+    trace_fatal!(
+        "Frames: {} used, {} free, {} total",
+        frames.used,
+        frames.free,
+        frames.total
+    );
+    memory::warn_unmanaged(&boot_info.memory_map);
     set_trace_level!(TraceLevel::Debug);
 
+    let mut heap_size = HEAP_ALLOCATOR.stats().size;
     loop {
         msleep(200);
-        if let Some(f) = tasks::NEW_TASKS.lock().pop() {
-            let mut name: char;
-            if f == x86_64::VirtualAddress(tetris as usize) {
-                name = 't';
-            } else if f == x86_64::VirtualAddress(uptime_temp as usize) {
-                name = 'u';
-            } else {
-                name = 'm';
+        let heap = HEAP_ALLOCATOR.stats();
+        if heap.size != heap_size {
+            heap_size = heap.size;
+            trace_info!(
+                "heap grew to {} bytes, {} used, {} peak",
+                heap.size,
+                heap.used,
+                heap.peak
+            );
+        }
+
+        // free the stacks of finished tasks
+        let stacks = features::without_interrupts(|| {
+            mem::replace(&mut *tasks::FINISHED_STACKS.lock(), Vec::new())
+        });
+        if !stacks.is_empty() {
+            for stack in stacks {
+                memory_controller.free_stack(stack);
+            }
+            trace_debug!(
+                "freed stacks, {} frames free",
+                memory_controller.frame_stats().free
+            );
+        }
+        let spaces = features::without_interrupts(|| {
+            mem::replace(&mut *tasks::FINISHED_ADDRESS_SPACES.lock(), Vec::new())
+        });
+        for space in spaces {
+            memory_controller.free_address_space(space);
+        }
+
+        // the task stays in `NEW_TASKS` until it is started, so it can still be killed meanwhile
+        let new_task = features::without_interrupts(|| tasks::NEW_TASKS.lock().last().cloned());
+        if let Some(new_task) = new_task {
+            let (memory, kernel_stack, address_space) =
+                match alloc_task_memory(&mut memory_controller, &new_task) {
+                    Some(task_memory) => task_memory,
+                    None => {
+                        // the handle gets `NO_MEMORY`, unless the task was killed meanwhile
+                        tasks::discard(new_task.pid, tasks::NO_MEMORY);
+                        trace_error!("not enough memory to start task {}", new_task.pid);
+                        continue;
+                    }
+                };
+            let task = new_task.create(memory, kernel_stack, address_space);
+            // the scheduler locks `TASKS` too, so the interrupts have to be disabled
+            let killed = features::without_interrupts(|| {
+                let mut new_tasks = tasks::NEW_TASKS.lock();
+                if new_tasks.last().map(|t| t.pid) != Some(new_task.pid) {
+                    return Some(task);
+                }
+                new_tasks.pop();
+                scheduler::add_task(task);
+                None
+            });
+            match killed {
+                None => trace_warn!("added new task {}", new_task.pid),
+                Some(task) => {
+                    trace_warn!("task {} was killed before it started", new_task.pid);
+                    if let Some(stack) = task.stack {
+                        memory_controller.free_stack(stack);
+                    }
+                    if let Some(stack) = task.kernel_stack {
+                        memory_controller.free_stack(stack);
+                    }
+                    if let Some(space) = task.address_space {
+                        memory_controller.free_address_space(space);
+                    }
+                }
             }
-            trace_warn!("added new task");
-            let memory = memory_controller
-                .alloc_stack(4)
-                .expect("can't allocate stack");
-            scheduler::TASKS.lock().push(tasks::TaskData::new(
-                name,
-                0,
-                x86_64::VirtualAddress(memory.top()),
-                f,
-                tasks::TaskStatus::READY,
-            ));
         }
     }
 }
 
+/// Allocates the stack of a new task, and its kernel stack and address space if it needs them. If
+/// an allocation fails, the memory which was already allocated is freed again.
+///
+/// # Arguments
+/// * `memory_controller` - (&mut MemoryController) The memory controller of the main task.
+/// * `task` - (&NewTask) The task which is started.
+///
+/// # Return
+/// * `Option<(Stack, Option<Stack>, Option<AddressSpace>)>` - The stack, kernel stack and address
+/// space of the task, `None` if there is not enough memory.
+fn alloc_task_memory(
+    memory_controller: &mut MemoryController,
+    task: &tasks::NewTask,
+) -> Option<(Stack, Option<Stack>, Option<AddressSpace>)> {
+    let stack = memory_controller.alloc_stack(task.stack_pages)?;
+    let kernel_stack = if task.params.user {
+        match memory_controller.alloc_stack(tasks::KERNEL_STACK_PAGES) {
+            Some(kernel_stack) => Some(kernel_stack),
+            None => {
+                memory_controller.free_stack(stack);
+                return None;
+            }
+        }
+    } else {
+        None
+    };
+    let address_space = if task.params.isolated {
+        match memory_controller.create_address_space(&stack, kernel_stack.as_ref()) {
+            Some(address_space) => Some(address_space),
+            None => {
+                memory_controller.free_stack(stack);
+                if let Some(kernel_stack) = kernel_stack {
+                    memory_controller.free_stack(kernel_stack);
+                }
+                return None;
+            }
+        }
+    } else {
+        None
+    };
+    Some((stack, kernel_stack, address_space))
+}
+
+/// Defines the scheduling policy which is used by the scheduler. Change it to compare the policies
+/// on the same task set.
+const SCHEDULING_POLICY: scheduler::Policy = scheduler::Policy::Edf;
+
 /// Defines where the heap starts.
 pub const HEAP_START: usize = 0o_000_001_000_000_0000;
-/// Defines the heap size. Currently 300 KiB are used.
+/// Defines the initial heap size. Currently 300 KiB are used.
 pub const HEAP_SIZE: usize = 300 * 1024; // 300 KiB
-                                         //
+/// Defines the maximum heap size. The heap grows on demand up to this size, it must not reach
+/// the stack area (see `memory::STACK_AREA_START`).
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64 MiB
+/// Defines if the heap counts the used bytes of every task. This costs a header of 16 bytes per
+/// allocation, but leaks of finished tasks are reported.
+pub const HEAP_TRACK_TASKS: bool = true;
+
 //#[global_allocator]
 //static HEAP_ALLOCATOR: BumpAllocator = BumpAllocator::new(HEAP_START, HEAP_START + HEAP_SIZE);
 
-use linked_list_allocator::LockedHeap;
+use memory::KernelHeap;
 
 /// Defines the global heap allocator. This system uses the linked list allocator from
-/// Phil Oppermann.
-#[global_allocator]
-static HEAP_ALLOCATOR: LockedHeap = LockedHeap::empty();
+/// Phil Oppermann, which maps more pages when it runs out of memory. The tests run on the host and
+/// use its allocator.
+#[cfg_attr(not(test), global_allocator)]
+static HEAP_ALLOCATOR: KernelHeap = KernelHeap::empty();
 
 /// Prints a welcome message on the screen.
 fn print_welcome(vendor_info: String, brand_info: String) {
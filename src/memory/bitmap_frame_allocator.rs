@@ -0,0 +1,312 @@
+//! Physical frame allocator with a bitmap.
+//! Every frame is represented by one bit, which is set if the frame is used. The bitmap is built
+//! from the memory map of the bootloader: only frames of `Usable` regions are free at the start,
+//! everything else (kernel, page tables, bootloader, ...) stays used. A second bitmap marks the
+//! usable frames, so frames which were never free can't be deallocated. Frames above
+//! `MAX_PHYSICAL_MEMORY` are not managed (see `warn_unmanaged()`).
+//!
+//! The bitmaps are static arrays, because the allocator is needed to map the heap. Each has a
+//! size of 32 KiB for 1 GiB of physical memory.
+use core::cmp;
+use memory::{Frame, FrameAllocator, PAGE_SIZE};
+use os_bootinfo::{MemoryMap, MemoryRegionType};
+
+/// Physical memory which is managed by the allocator.
+const MAX_PHYSICAL_MEMORY: usize = 1024 * 1024 * 1024;
+/// Number of frames which can be managed.
+const MAX_FRAMES: usize = MAX_PHYSICAL_MEMORY / PAGE_SIZE;
+/// Number of bits of a bitmap word.
+const WORD_BITS: usize = 64;
+
+/// Bitmap of all frames, a set bit marks a used frame. Only used by the `BitmapFrameAllocator`.
+static mut FRAME_BITMAP: [u64; MAX_FRAMES / WORD_BITS] = [!0; MAX_FRAMES / WORD_BITS];
+/// Bitmap of all frames, a set bit marks a usable frame. Only used by the `BitmapFrameAllocator`.
+static mut USABLE_BITMAP: [u64; MAX_FRAMES / WORD_BITS] = [0; MAX_FRAMES / WORD_BITS];
+
+/// Number of frames of the allocator.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    /// Usable frames of the memory map.
+    pub total: usize,
+    /// Allocated frames.
+    pub used: usize,
+    /// Frames which can be allocated.
+    pub free: usize,
+}
+
+/// Frame allocator which supports deallocation and contiguous allocations.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64; MAX_FRAMES / WORD_BITS],
+    /// Frames of `Usable` regions, only these are allocated and deallocated.
+    usable: &'static mut [u64; MAX_FRAMES / WORD_BITS],
+    /// Word where the search for a free frame starts. All words before are full.
+    next_word: usize,
+    /// Number of usable frames.
+    total: usize,
+    /// Number of allocated usable frames.
+    used: usize,
+}
+
+impl FrameAllocator for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        for word in self.next_word..self.bitmap.len() {
+            if self.bitmap[word] != !0 {
+                self.next_word = word;
+                let bit = (!self.bitmap[word]).trailing_zeros() as usize;
+                let number = word * WORD_BITS + bit;
+                self.set_used(number, true);
+                self.used += 1;
+                return Some(Frame { number });
+            }
+        }
+        self.next_word = self.bitmap.len();
+        None
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        assert!(
+            frame.number < MAX_FRAMES && self.is_usable(frame.number),
+            "frame {} is not usable",
+            frame.number
+        );
+        assert!(
+            self.is_used(frame.number),
+            "frame {} is not allocated",
+            frame.number
+        );
+        self.set_used(frame.number, false);
+        self.used -= 1;
+    }
+}
+
+impl BitmapFrameAllocator {
+    /// Creates the allocator. Must only be called once, because the bitmaps are static.
+    ///
+    /// # Arguments
+    /// * `memory_map` - (&'static MemoryMap) Memory map of the bootloader.
+    pub unsafe fn new(memory_map: &'static MemoryMap) -> BitmapFrameAllocator {
+        BitmapFrameAllocator::with_regions(
+            &mut FRAME_BITMAP,
+            &mut USABLE_BITMAP,
+            memory_map
+                .iter()
+                .filter(|region| region.region_type == MemoryRegionType::Usable)
+                .map(|region| {
+                    (
+                        region.range.start_addr() as usize / PAGE_SIZE,
+                        region.range.end_addr() as usize / PAGE_SIZE,
+                    )
+                }),
+        )
+    }
+
+    /// Creates the allocator with the given bitmaps, only the frames of `regions` are free.
+    ///
+    /// # Arguments
+    /// * `bitmap` - (&'static mut [u64]) Bitmap of the used frames, all bits must be set.
+    /// * `usable` - (&'static mut [u64]) Bitmap of the usable frames, all bits must be cleared.
+    /// * `regions` - (Iterator<Item = (usize, usize)>) Usable regions, given by their first frame
+    /// and the frame after their end.
+    fn with_regions<I>(
+        bitmap: &'static mut [u64; MAX_FRAMES / WORD_BITS],
+        usable: &'static mut [u64; MAX_FRAMES / WORD_BITS],
+        regions: I,
+    ) -> BitmapFrameAllocator
+    where
+        I: Iterator<Item = (usize, usize)>,
+    {
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            usable,
+            next_word: 0,
+            total: 0,
+            used: 0,
+        };
+        for (start, end) in regions {
+            for number in start..end {
+                // the frame zero is never used, a null pointer would be valid otherwise
+                if number != 0 && number < MAX_FRAMES && !allocator.is_usable(number) {
+                    allocator.usable[number / WORD_BITS] |= 1 << (number % WORD_BITS);
+                    allocator.set_used(number, false);
+                    allocator.total += 1;
+                }
+            }
+        }
+        allocator.next_word = 0;
+        allocator
+    }
+
+    /// Allocates physically contiguous frames, e.g. for devices which use dma.
+    ///
+    /// # Arguments
+    /// * `count` - (usize) Number of frames.
+    ///
+    /// # Return
+    /// * `Option<Frame>` - The first frame, `None` if there are not enough contiguous free frames.
+    #[allow(dead_code)]
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<Frame> {
+        if count == 0 {
+            return None;
+        }
+        let mut start = self.next_word * WORD_BITS;
+        let mut length = 0;
+        for number in self.next_word * WORD_BITS..MAX_FRAMES {
+            if self.is_used(number) {
+                start = number + 1;
+                length = 0;
+                continue;
+            }
+            length += 1;
+            if length == count {
+                for frame in start..start + count {
+                    self.set_used(frame, true);
+                }
+                self.used += count;
+                return Some(Frame { number: start });
+            }
+        }
+        None
+    }
+
+    /// Frees frames which were allocated with `allocate_contiguous()`.
+    ///
+    /// # Arguments
+    /// * `first` - (Frame) The first frame.
+    /// * `count` - (usize) Number of frames.
+    #[allow(dead_code)]
+    pub fn deallocate_contiguous(&mut self, first: Frame, count: usize) {
+        for number in first.number..first.number + count {
+            self.deallocate_frame(Frame { number });
+        }
+    }
+
+    /// Returns the number of total, used and free frames.
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.total,
+            used: self.used,
+            free: self.total - self.used,
+        }
+    }
+
+    /// Returns `true` if the frame belongs to a `Usable` region of the memory map.
+    fn is_usable(&self, number: usize) -> bool {
+        self.usable[number / WORD_BITS] & (1 << (number % WORD_BITS)) != 0
+    }
+
+    /// Returns `true` if the frame is allocated or not usable.
+    fn is_used(&self, number: usize) -> bool {
+        self.bitmap[number / WORD_BITS] & (1 << (number % WORD_BITS)) != 0
+    }
+
+    /// Marks a frame as used or free.
+    fn set_used(&mut self, number: usize, used: bool) {
+        let mask = 1 << (number % WORD_BITS);
+        if used {
+            self.bitmap[number / WORD_BITS] |= mask;
+        } else {
+            self.bitmap[number / WORD_BITS] &= !mask;
+            if number / WORD_BITS < self.next_word {
+                self.next_word = number / WORD_BITS;
+            }
+        }
+    }
+}
+
+/// Warns about usable memory above `MAX_PHYSICAL_MEMORY`, which the allocator does not manage.
+/// Called at boot after the heap is initialized, because the traces need it.
+///
+/// # Arguments
+/// * `memory_map` - (&MemoryMap) Memory map of the bootloader.
+pub fn warn_unmanaged(memory_map: &MemoryMap) {
+    for region in memory_map
+        .iter()
+        .filter(|region| region.region_type == MemoryRegionType::Usable)
+    {
+        let start = region.range.start_addr() as usize;
+        let end = region.range.end_addr() as usize;
+        if end > MAX_PHYSICAL_MEMORY {
+            trace_warn!(
+                "usable memory 0x{:x} - 0x{:x} is above 0x{:x} and not used",
+                cmp::max(start, MAX_PHYSICAL_MEMORY),
+                end,
+                MAX_PHYSICAL_MEMORY
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Creates an allocator with its own bitmaps, which are leaked because they must be static.
+    fn allocator(regions: &[(usize, usize)]) -> BitmapFrameAllocator {
+        let bitmap = Box::into_raw(Box::new([!0u64; MAX_FRAMES / WORD_BITS]));
+        let usable = Box::into_raw(Box::new([0u64; MAX_FRAMES / WORD_BITS]));
+        unsafe {
+            BitmapFrameAllocator::with_regions(&mut *bitmap, &mut *usable, regions.iter().cloned())
+        }
+    }
+
+    #[test]
+    fn allocates_only_usable_frames() {
+        let mut allocator = allocator(&[(0, 4), (100, 102)]);
+        // the frame zero is never used
+        assert_eq!(allocator.stats().total, 5);
+        let frames: Vec<usize> = (0..5)
+            .map(|_| allocator.allocate_frame().expect("frame missing").number)
+            .collect();
+        assert_eq!(frames, vec![1, 2, 3, 100, 101]);
+        assert_eq!(allocator.allocate_frame(), None);
+        assert_eq!(allocator.stats().used, 5);
+        assert_eq!(allocator.stats().free, 0);
+    }
+
+    #[test]
+    fn reuses_deallocated_frames() {
+        let mut allocator = allocator(&[(10, 20)]);
+        let first = allocator.allocate_frame().expect("frame missing");
+        let second = allocator.allocate_frame().expect("frame missing");
+        assert_eq!(second.number, 11);
+        allocator.deallocate_frame(first);
+        assert_eq!(allocator.stats().used, 1);
+        assert_eq!(allocator.allocate_frame(), Some(Frame { number: 10 }));
+        assert_eq!(allocator.allocate_frame(), Some(Frame { number: 12 }));
+    }
+
+    #[test]
+    fn allocates_contiguous_frames() {
+        let mut allocator = allocator(&[(1, 4), (6, 12)]);
+        assert_eq!(allocator.allocate_frame(), Some(Frame { number: 1 }));
+        // the frames 2 and 3 are too few, the gap at 4 and 5 is not usable
+        let first = allocator
+            .allocate_contiguous(4)
+            .expect("no contiguous frames");
+        assert_eq!(first.number, 6);
+        assert_eq!(allocator.stats().used, 5);
+        assert_eq!(allocator.allocate_contiguous(3), None);
+        assert_eq!(allocator.allocate_contiguous(0), None);
+        allocator.deallocate_contiguous(first, 4);
+        assert_eq!(allocator.stats().used, 1);
+        assert_eq!(allocator.allocate_contiguous(6), Some(Frame { number: 6 }));
+    }
+
+    #[test]
+    #[should_panic(expected = "is not usable")]
+    fn deallocating_an_unusable_frame_panics() {
+        let mut allocator = allocator(&[(1, 4)]);
+        allocator.deallocate_frame(Frame { number: 5 });
+    }
+
+    #[test]
+    #[should_panic(expected = "is not allocated")]
+    fn deallocating_a_free_frame_panics() {
+        let mut allocator = allocator(&[(1, 4)]);
+        let frame = allocator.allocate_frame().expect("frame missing");
+        allocator.deallocate_frame(Frame {
+            number: frame.number,
+        });
+        allocator.deallocate_frame(frame);
+    }
+}
@@ -0,0 +1,336 @@
+//! Growable kernel heap.
+//! The heap uses the linked list allocator and starts with a small mapped area. If an allocation
+//! fails, the heap maps more pages behind its end and tries again, until the maximum size is
+//! reached. Pages are never unmapped again.
+//!
+//! The heap is locked with disabled interrupts, so an interrupt handler can't deadlock on the
+//! lock of a preempted task.
+//!
+//! The allocation time of the heap depends on its fragmentation. Real-time tasks can forbid the
+//! heap after their initialization (see `tasks::forbid_heap()`) and use block pools instead. An
+//! allocation while such a task runs is a bug and causes a panic.
+//!
+//! The heap counts the allocated bytes, the peak and the number of allocations (see `stats()`).
+//! If `HEAP_TRACK_TASKS` is set, every allocation gets a header with the pid of the task which
+//! allocated it, so the used bytes of every task are known (see `task_stats()`). Memory which is
+//! still allocated when a task exits is reported by the scheduler as a leak.
+use alloc::Vec;
+use core::alloc::{GlobalAlloc, Layout, Opaque};
+use core::cmp::max;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use features::without_interrupts;
+use linked_list_allocator::Heap;
+use memory::{map_pages, PAGE_SIZE};
+use spin::Mutex;
+use HEAP_TRACK_TASKS;
+
+/// Minimum number of pages which are mapped when the heap grows.
+const HEAP_GROW_PAGES: usize = 16;
+/// Size of the header in front of every allocation if `HEAP_TRACK_TASKS` is set. It stores the pid
+/// of the owner and keeps the alignment of the allocation.
+const HEADER_SIZE: usize = 16;
+/// Maximum number of tasks with own statistics. Allocations of further tasks are only counted in
+/// the total statistics.
+const TRACKED_TASKS: usize = 16;
+
+/// Set while a task runs which forbids the heap. Updated by the scheduler on every task switch.
+static HEAP_FORBIDDEN: AtomicBool = AtomicBool::new(false);
+/// Pid of the task which owns new allocations. Updated by the scheduler on every task switch.
+static HEAP_OWNER: AtomicUsize = AtomicUsize::new(0);
+
+/// Forbids or allows heap allocations until the next task switch.
+///
+/// # Arguments
+/// * `forbidden` - (bool) `true` if allocations cause a panic.
+pub fn set_heap_forbidden(forbidden: bool) {
+    HEAP_FORBIDDEN.store(forbidden, Ordering::SeqCst);
+}
+
+/// Sets the task which owns new allocations until the next task switch. The scheduler and the
+/// main task use the pid `0`.
+///
+/// # Arguments
+/// * `pid` - (usize) Pid of the running task.
+pub fn set_heap_owner(pid: usize) {
+    HEAP_OWNER.store(pid, Ordering::SeqCst);
+}
+
+/// Statistics of the whole heap.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Mapped size of the heap in bytes.
+    pub size: usize,
+    /// Maximum size of the heap in bytes.
+    pub max_size: usize,
+    /// Allocated bytes, without the headers.
+    pub used: usize,
+    /// Maximum of `used` since the start.
+    pub peak: usize,
+    /// Number of allocations since the start.
+    pub allocations: usize,
+    /// Number of deallocations since the start.
+    pub frees: usize,
+    /// Number of allocations which failed, because the heap could not grow anymore.
+    pub failures: usize,
+}
+
+/// Heap statistics of a task.
+#[derive(Debug, Clone, Copy)]
+pub struct TaskHeapStats {
+    /// Pid of the task.
+    pub pid: usize,
+    /// Bytes which are allocated by the task.
+    pub used: usize,
+    /// Maximum of `used` since the start of the task.
+    pub peak: usize,
+    /// Number of allocations of the task.
+    pub allocations: usize,
+}
+
+struct HeapState {
+    heap: Heap,
+    /// Maximum size of the heap in bytes.
+    max_size: usize,
+    stats: HeapStats,
+    /// Statistics of the tasks which own allocations, only used if `HEAP_TRACK_TASKS` is set.
+    tasks: [Option<TaskHeapStats>; TRACKED_TASKS],
+}
+
+/// Global heap allocator which grows on demand.
+pub struct KernelHeap {
+    state: Mutex<HeapState>,
+}
+
+impl KernelHeap {
+    /// Creates an empty heap. `init()` must be called before the first allocation.
+    pub const fn empty() -> KernelHeap {
+        KernelHeap {
+            state: Mutex::new(HeapState {
+                heap: Heap::empty(),
+                max_size: 0,
+                stats: HeapStats {
+                    size: 0,
+                    max_size: 0,
+                    used: 0,
+                    peak: 0,
+                    allocations: 0,
+                    frees: 0,
+                    failures: 0,
+                },
+                tasks: [None; TRACKED_TASKS],
+            }),
+        }
+    }
+
+    /// Initializes the heap. The initial area must be mapped already.
+    ///
+    /// # Arguments
+    /// * `start` - (usize) Start address of the heap.
+    /// * `size` - (usize) Size of the mapped initial area.
+    /// * `max_size` - (usize) Maximum size of the heap, the virtual area behind `start` must be
+    /// unused up to this size.
+    pub unsafe fn init(&self, start: usize, size: usize, max_size: usize) {
+        assert!(size <= max_size);
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            state.heap.init(start, size);
+            state.max_size = max_size;
+        });
+    }
+
+    /// Returns `true` if the heap is locked, e.g. by a task which faulted inside the allocator.
+    /// Used by exception handlers, which must not wait for the lock.
+    pub fn is_locked(&self) -> bool {
+        self.state.try_lock().is_none()
+    }
+
+    /// Returns the statistics of the whole heap.
+    pub fn stats(&self) -> HeapStats {
+        without_interrupts(|| {
+            let state = self.state.lock();
+            HeapStats {
+                size: state.heap.size(),
+                max_size: state.max_size,
+                ..state.stats
+            }
+        })
+    }
+
+    /// Returns the statistics of all tasks which own allocations, ordered by pid. Empty if
+    /// `HEAP_TRACK_TASKS` is not set.
+    ///
+    /// # Return
+    /// * `Vec<TaskHeapStats>` - The statistics of the tasks.
+    pub fn task_stats(&self) -> Vec<TaskHeapStats> {
+        // the entries are copied first, the heap must not be used while it is locked
+        let tasks = without_interrupts(|| self.state.lock().tasks);
+        let mut stats: Vec<TaskHeapStats> = tasks.iter().filter_map(|task| *task).collect();
+        stats.sort_by_key(|task| task.pid);
+        stats
+    }
+
+    /// Removes the statistics of a finished task. Called by the scheduler to detect leaks.
+    ///
+    /// # Arguments
+    /// * `pid` - (usize) Pid of the finished task.
+    ///
+    /// # Return
+    /// * `Option<TaskHeapStats>` - The last statistics of the task, `None` if it never owned an
+    /// allocation.
+    pub fn task_exited(&self, pid: usize) -> Option<TaskHeapStats> {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            let entry = state
+                .tasks
+                .iter_mut()
+                .find(|task| task.map(|task| task.pid) == Some(pid))?;
+            entry.take()
+        })
+    }
+}
+
+impl HeapState {
+    /// Maps more pages behind the end of the heap, so that an allocation of `layout` fits.
+    ///
+    /// Called by `alloc()` with the heap lock held, so `memory::map_pages()` and the frame
+    /// allocator must not use the heap: an allocation on this path would deadlock on the lock.
+    /// The heap is forbidden while the pages are mapped, so such an allocation panics instead.
+    ///
+    /// # Return
+    /// * `bool` - `false` if the maximum size is reached or there are not enough free frames.
+    fn grow(&mut self, layout: &Layout) -> bool {
+        let needed = (layout.size() + layout.align() + PAGE_SIZE - 1) / PAGE_SIZE;
+        let pages = max(needed, HEAP_GROW_PAGES);
+        let size = self.heap.size();
+        let pages = if size + pages * PAGE_SIZE <= self.max_size {
+            pages
+        } else if size + needed * PAGE_SIZE <= self.max_size {
+            needed
+        } else {
+            return false;
+        };
+
+        HEAP_FORBIDDEN.store(true, Ordering::SeqCst);
+        let mapped = map_pages(self.heap.top(), pages);
+        HEAP_FORBIDDEN.store(false, Ordering::SeqCst);
+        if !mapped {
+            return false;
+        }
+        unsafe {
+            self.heap.extend(pages * PAGE_SIZE);
+        }
+        true
+    }
+
+    /// Counts an allocation of `size` bytes by the task `owner`.
+    fn count_alloc(&mut self, owner: usize, size: usize) {
+        self.stats.used += size;
+        self.stats.allocations += 1;
+        self.stats.peak = max(self.stats.peak, self.stats.used);
+        if !HEAP_TRACK_TASKS {
+            return;
+        }
+
+        let index = match self
+            .tasks
+            .iter()
+            .position(|task| task.map(|task| task.pid) == Some(owner))
+        {
+            Some(index) => index,
+            None => match self.tasks.iter().position(|task| task.is_none()) {
+                Some(index) => {
+                    self.tasks[index] = Some(TaskHeapStats {
+                        pid: owner,
+                        used: 0,
+                        peak: 0,
+                        allocations: 0,
+                    });
+                    index
+                }
+                None => return, /* too many tasks */
+            },
+        };
+        if let Some(ref mut task) = self.tasks[index] {
+            task.used += size;
+            task.allocations += 1;
+            task.peak = max(task.peak, task.used);
+        }
+    }
+
+    /// Counts a deallocation of `size` bytes which were allocated by the task `owner`.
+    fn count_free(&mut self, owner: usize, size: usize) {
+        self.stats.used -= size;
+        self.stats.frees += 1;
+        for task in self.tasks.iter_mut() {
+            match *task {
+                Some(ref mut task) if task.pid == owner => {
+                    task.used = task.used.saturating_sub(size);
+                    return;
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Returns the layout which is allocated for `layout`, including the header if `HEAP_TRACK_TASKS`
+/// is set, and the offset of the memory behind the header.
+fn with_header(layout: &Layout) -> (Layout, usize) {
+    if !HEAP_TRACK_TASKS {
+        return (layout.clone(), 0);
+    }
+    // the header is placed right in front of the returned memory, so it keeps its alignment
+    let offset = max(HEADER_SIZE, layout.align());
+    let layout = unsafe {
+        Layout::from_size_align_unchecked(layout.size() + offset, max(layout.align(), HEADER_SIZE))
+    };
+    (layout, offset)
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut Opaque {
+        if HEAP_FORBIDDEN.swap(false, Ordering::SeqCst) {
+            panic!(
+                "heap allocation of {} bytes by a task which forbids the heap",
+                layout.size()
+            );
+        }
+        let (full_layout, offset) = with_header(&layout);
+        let owner = HEAP_OWNER.load(Ordering::SeqCst);
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            loop {
+                if let Ok(allocation) = state.heap.allocate_first_fit(full_layout.clone()) {
+                    let address = allocation.as_ptr() as usize + offset;
+                    if offset != 0 {
+                        *((address - HEADER_SIZE) as *mut usize) = owner;
+                    }
+                    state.count_alloc(owner, layout.size());
+                    return address as *mut Opaque;
+                }
+                if !state.grow(&full_layout) {
+                    state.stats.failures += 1;
+                    return 0 as *mut Opaque;
+                }
+            }
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut Opaque, layout: Layout) {
+        let (full_layout, offset) = with_header(&layout);
+        let owner = if offset != 0 {
+            *((ptr as usize - HEADER_SIZE) as *const usize)
+        } else {
+            0
+        };
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            state.count_free(owner, layout.size());
+            state.heap.deallocate(
+                NonNull::new_unchecked((ptr as usize - offset) as *mut Opaque),
+                full_layout,
+            )
+        })
+    }
+}
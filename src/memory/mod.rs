@@ -1,11 +1,21 @@
 //! Code of the `blog-os by phil oppermann`
-pub use self::area_frame_allocator::AreaFrameAllocator;
+pub use self::bitmap_frame_allocator::{warn_unmanaged, BitmapFrameAllocator, FrameStats};
+pub use self::heap::{set_heap_forbidden, set_heap_owner, HeapStats, KernelHeap, TaskHeapStats};
+pub use self::paging::AddressSpace;
 use self::paging::PhysicalAddress;
+pub use self::pool::{Block, BlockPool};
 pub use self::stack_allocator::Stack;
+use alloc::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use features::without_interrupts;
+use spin::Mutex;
+use x86_64::registers::control_regs;
 
-mod area_frame_allocator;
+mod bitmap_frame_allocator;
+mod heap;
 //pub mod heap_allocator;
 mod paging;
+mod pool;
 mod stack_allocator;
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -15,6 +25,13 @@ pub struct Frame {
 
 pub const PAGE_SIZE: usize = 4096;
 
+/// Start of the virtual area of the task stacks, behind the area of the heap.
+pub const STACK_AREA_START: usize = 0o_000_002_000_000_0000;
+/// Size of the virtual area of the task stacks (1 GiB). Only the used part is mapped.
+pub const STACK_AREA_SIZE: usize = 1024 * 1024 * 1024;
+/// Page which is used to write page tables which are not active.
+const TEMPORARY_PAGE: usize = 0xcafe_babe_000;
+
 impl Frame {
     fn containing_address(address: usize) -> Frame {
         Frame {
@@ -39,20 +56,209 @@ pub trait FrameAllocator {
     fn deallocate_frame(&mut self, frame: Frame);
 }
 
-pub struct MemoryController {
+/// Page table and frame allocator. They are global, because the heap maps new pages when it
+/// grows (see `map_pages()`). Only locked with disabled interrupts and never held while the heap
+/// is used, otherwise a growing heap would deadlock.
+struct Paging {
     active_table: paging::ActivePageTable,
-    frame_allocator: AreaFrameAllocator,
+    frame_allocator: BitmapFrameAllocator,
+    temporary_page: paging::TemporaryPage,
+}
+
+static PAGING: Mutex<Option<Paging>> = Mutex::new(None);
+
+/// Physical address of the P4 table of the kernel, `0` before `init()`.
+static KERNEL_P4: AtomicUsize = AtomicUsize::new(0);
+
+pub struct MemoryController {
     stack_allocator: stack_allocator::StackAllocator,
+    /// Stacks which are mapped in every address space, see `share_stack()`.
+    shared_stacks: Vec<Stack>,
 }
 
 impl MemoryController {
     pub fn alloc_stack(&mut self, size_in_pages: usize) -> Option<Stack> {
-        let &mut MemoryController {
-            ref mut active_table,
-            ref mut frame_allocator,
-            ref mut stack_allocator,
-        } = self;
-        stack_allocator.alloc_stack(active_table, frame_allocator, size_in_pages)
+        let stack = self.stack_allocator.alloc_stack(size_in_pages)?;
+        if !map_pages(stack.bottom(), stack.size_in_pages()) {
+            self.stack_allocator.free_stack(stack);
+            return None;
+        }
+        unsafe {
+            stack.paint();
+        }
+        Some(stack)
+    }
+
+    /// Frees the stack of a finished task. Its frames can be used for new stacks.
+    pub fn free_stack(&mut self, stack: Stack) {
+        unmap_pages(stack.bottom(), stack.size_in_pages());
+        self.stack_allocator.free_stack(stack)
+    }
+
+    /// Maps a stack in every address space which is created afterwards, e.g. a stack of the
+    /// interrupt stack table. The stack must never be freed.
+    pub fn share_stack(&mut self, stack: &Stack) {
+        self.shared_stacks.push(stack.clone());
+    }
+
+    /// Creates the address space of an isolated task. It contains the kernel, the stack of the
+    /// task and the shared stacks, but not the heap and the other stacks.
+    ///
+    /// # Arguments
+    /// * `stack` - (&Stack) The stack of the task.
+    /// * `kernel_stack` - (Option<&Stack>) Kernel stack of a user task, `None` for tasks which
+    /// run in ring 0. If it is given, only `stack` is accessible from ring 3.
+    ///
+    /// # Return
+    /// * `Option<AddressSpace>` - The new address space, `None` if there are not enough frames.
+    pub fn create_address_space(
+        &mut self,
+        stack: &Stack,
+        kernel_stack: Option<&Stack>,
+    ) -> Option<AddressSpace> {
+        use self::paging::Page;
+        let user = kernel_stack.is_some();
+        let shared = &self.shared_stacks;
+        let stacks = move || {
+            shared
+                .iter()
+                .map(|stack| (stack, false))
+                .chain(kernel_stack.map(|stack| (stack, false)))
+                .chain(Some((stack, user)))
+        };
+        let count: usize = stacks().map(|(stack, _)| stack.size_in_pages()).sum();
+        // allocated before the lock, the heap must not be used while it is held
+        let mut pages: Vec<(usize, usize, bool)> = Vec::with_capacity(count);
+        without_interrupts(|| {
+            let mut guard = PAGING.lock();
+            let state = guard.as_mut().expect("memory is not initialized");
+            // the P4, P3 and P2 table and up to two P1 tables per stack
+            if state.frame_allocator.stats().free < 3 + 2 * stacks().count() {
+                return None;
+            }
+            for (stack, user) in stacks() {
+                let first = Page::containing_address(stack.bottom());
+                let last = Page::containing_address(stack.top() - 1);
+                for page in Page::range_inclusive(first, last) {
+                    let frame = state
+                        .active_table
+                        .translate_page(page)
+                        .expect("stack is not mapped");
+                    pages.push((page.start_address(), frame.start_address(), user));
+                }
+            }
+            let Paging {
+                ref mut active_table,
+                ref mut frame_allocator,
+                ref mut temporary_page,
+            } = *state;
+            paging::address_space::create(active_table, temporary_page, frame_allocator, &pages)
+        })
+    }
+
+    /// Allows ring 3 to access the mapped pages of an area of the kernel, e.g. the code of user
+    /// tasks. The change is made in the kernel table, so it is inherited by all address spaces
+    /// which are created afterwards.
+    ///
+    /// # Arguments
+    /// * `start` - (usize) Start address of the area.
+    /// * `end` - (usize) End address of the area (exclusive).
+    pub fn set_user_accessible(&mut self, start: usize, end: usize) {
+        use self::paging::Page;
+        if start >= end {
+            return;
+        }
+        without_interrupts(|| {
+            let mut guard = PAGING.lock();
+            let state = guard.as_mut().expect("memory is not initialized");
+            let first = Page::containing_address(start);
+            let last = Page::containing_address(end - 1);
+            for page in Page::range_inclusive(first, last) {
+                state.active_table.set_user_accessible(page);
+            }
+        })
+    }
+
+    /// Frees the page tables of an address space. The task of the address space must be finished.
+    pub fn free_address_space(&mut self, space: AddressSpace) {
+        without_interrupts(|| {
+            let mut guard = PAGING.lock();
+            let state = guard.as_mut().expect("memory is not initialized");
+            let Paging {
+                ref mut active_table,
+                ref mut frame_allocator,
+                ref mut temporary_page,
+            } = *state;
+            paging::address_space::free(active_table, temporary_page, frame_allocator, space)
+        })
+    }
+
+    /// Returns the number of total, used and free physical frames.
+    pub fn frame_stats(&self) -> FrameStats {
+        without_interrupts(|| {
+            PAGING
+                .lock()
+                .as_ref()
+                .expect("memory is not initialized")
+                .frame_allocator
+                .stats()
+        })
+    }
+}
+
+/// Maps pages to new frames.
+///
+/// # Arguments
+/// * `start` - (usize) Address of the first page.
+/// * `count` - (usize) Number of pages.
+///
+/// # Return
+/// * `bool` - `false` if there are not enough free frames, nothing is mapped then.
+fn map_pages(start: usize, count: usize) -> bool {
+    use self::paging::Page;
+    without_interrupts(|| {
+        let mut guard = PAGING.lock();
+        let state = guard.as_mut().expect("memory is not initialized");
+        // the page tables of the new pages may need frames too
+        if state.frame_allocator.stats().free < count + count / 512 + 3 {
+            return false;
+        }
+        let first = Page::containing_address(start);
+        let last = Page::containing_address(start + count * PAGE_SIZE - 1);
+        for page in Page::range_inclusive(first, last) {
+            state
+                .active_table
+                .map(page, paging::WRITABLE, &mut state.frame_allocator);
+        }
+        true
+    })
+}
+
+/// Unmaps pages and returns their frames to the frame allocator.
+///
+/// # Arguments
+/// * `start` - (usize) Address of the first page.
+/// * `count` - (usize) Number of pages.
+fn unmap_pages(start: usize, count: usize) {
+    use self::paging::Page;
+    without_interrupts(|| {
+        let mut guard = PAGING.lock();
+        let state = guard.as_mut().expect("memory is not initialized");
+        let first = Page::containing_address(start);
+        let last = Page::containing_address(start + count * PAGE_SIZE - 1);
+        for page in Page::range_inclusive(first, last) {
+            state.active_table.unmap(page, &mut state.frame_allocator);
+        }
+    })
+}
+
+/// Switches to the address space of the kernel, if an isolated task was interrupted. Called by
+/// interrupt handlers before they use the heap or other stacks. The timer entry stub switches back
+/// to the address space of the next task.
+pub fn enter_kernel_space() {
+    let kernel = KERNEL_P4.load(Ordering::SeqCst);
+    if kernel != 0 && control_regs::cr3().0 as usize != kernel {
+        unsafe { control_regs::cr3_write(::x86_64::PhysicalAddress(kernel as u64)) };
     }
 }
 
@@ -63,9 +269,15 @@ pub fn init(boot_info: &'static BootInfo) -> MemoryController {
     assert_has_not_been_called!("memory::init must be called only once");
     let memory_map_tag = &boot_info.memory_map;
 
-    let mut frame_allocator = AreaFrameAllocator::new(memory_map_tag);
     unsafe {
+        let mut frame_allocator = BitmapFrameAllocator::new(memory_map_tag);
         let mut active_table = paging::ActivePageTable::new();
+
+        // the NXE bit is needed for the not executable data of the kernel
+        paging::enable_nxe_bit();
+        paging::remap_the_kernel(&mut active_table);
+        paging::enable_write_protect_bit();
+
         use {HEAP_SIZE, HEAP_START};
 
         let heap_start_page = Page::containing_address(HEAP_START);
@@ -75,17 +287,27 @@ pub fn init(boot_info: &'static BootInfo) -> MemoryController {
             active_table.map(page, paging::WRITABLE, &mut frame_allocator);
         }
 
-        let stack_allocator = {
-            let stack_alloc_start = heap_end_page + 1;
-            let stack_alloc_end = stack_alloc_start + 100;
-            let stack_alloc_range = Page::range_inclusive(stack_alloc_start, stack_alloc_end);
-            stack_allocator::StackAllocator::new(stack_alloc_range)
-        };
+        let temporary_page = paging::TemporaryPage::new(
+            Page::containing_address(TEMPORARY_PAGE),
+            &mut frame_allocator,
+        );
+        KERNEL_P4.store(control_regs::cr3().0 as usize, Ordering::SeqCst);
+        without_interrupts(|| {
+            *PAGING.lock() = Some(Paging {
+                active_table,
+                frame_allocator,
+                temporary_page,
+            })
+        });
+
+        let stack_allocator = stack_allocator::StackAllocator::new(
+            STACK_AREA_START,
+            STACK_AREA_START + STACK_AREA_SIZE,
+        );
 
         MemoryController {
-            active_table: active_table,
-            frame_allocator: frame_allocator,
             stack_allocator: stack_allocator,
+            shared_stacks: Vec::new(),
         }
     }
 }
@@ -0,0 +1,169 @@
+//! Address spaces of isolated tasks.
+//! An address space is a copy of the kernel page table (the *template*) with two exceptions:
+//!
+//!     1. The heap area is not mapped, so the task can't overwrite kernel data.
+//!     2. The stack area only contains the stack of the task and the shared stacks (e.g. the
+//!        stacks of the interrupt stack table), so the task can't overwrite other stacks.
+//!
+//! The kernel image, the vga buffer and all other areas share their page tables with the kernel.
+//! The heap and the stack area must be in the first P4 entry, only this entry gets an own P3
+//! table. The P2 and P1 tables of the stack area are created by `ActivePageTable::with()`.
+//!
+//! Interrupt handlers which need the heap switch to the kernel table first (see
+//! `memory::enter_kernel_space()`).
+//!
+//! The stack of a user task is mapped with `USER_ACCESSIBLE`, its kernel stack and the shared
+//! stacks are only accessible in ring 0.
+use super::table::{Level4, Table};
+use super::temporary_page::TemporaryPage;
+use super::{ActivePageTable, InactivePageTable, Page, PRESENT, WRITABLE};
+use memory::{Frame, FrameAllocator, STACK_AREA_SIZE, STACK_AREA_START};
+use {HEAP_MAX_SIZE, HEAP_START};
+
+/// Page table of an isolated task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressSpace {
+    /// Physical address of the P4 table, loaded into `cr3`.
+    p4: usize,
+}
+
+impl AddressSpace {
+    /// Returns the physical address of the P4 table, which is loaded into `cr3`.
+    pub fn p4_address(&self) -> usize {
+        self.p4
+    }
+}
+
+/// Returns `true` if the P3 entry of the first P4 entry covers the heap or the stack area. These
+/// entries are not copied from the template.
+fn is_private(p3_index: usize) -> bool {
+    let heap_first = Page::containing_address(HEAP_START).p3_index();
+    let heap_last = Page::containing_address(HEAP_START + HEAP_MAX_SIZE - 1).p3_index();
+    let stacks = Page::containing_address(STACK_AREA_START).p3_index();
+    (p3_index >= heap_first && p3_index <= heap_last) || p3_index == stacks
+}
+
+/// Creates an address space from the active kernel table. Must be called in the kernel address
+/// space and must not use the heap, because the caller holds the page table lock.
+///
+/// # Arguments
+/// * `active_table` - (&mut ActivePageTable) The kernel table.
+/// * `temporary_page` - (&mut TemporaryPage) Used to write the new tables.
+/// * `frame_allocator` - (&mut A) Allocator for the new tables.
+/// * `pages` - (&[(usize, usize, bool)]) Virtual and physical addresses of the pages of the stack
+/// area which are mapped in the new address space, and whether ring 3 may access them.
+///
+/// # Return
+/// * `Option<AddressSpace>` - The new address space, `None` if there are not enough frames.
+pub fn create<A: FrameAllocator>(
+    active_table: &mut ActivePageTable,
+    temporary_page: &mut TemporaryPage,
+    frame_allocator: &mut A,
+    pages: &[(usize, usize, bool)],
+) -> Option<AddressSpace> {
+    assert!(
+        Page::containing_address(HEAP_START).p4_index() == 0
+            && Page::containing_address(STACK_AREA_START + STACK_AREA_SIZE - 1).p4_index() == 0,
+        "heap and stacks must be in the first P4 entry"
+    );
+    let p4_frame = frame_allocator.allocate_frame()?;
+    let p3_frame = match frame_allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => {
+            frame_allocator.deallocate_frame(p4_frame);
+            return None;
+        }
+    };
+    let mut new_table = InactivePageTable::new(p4_frame.clone(), active_table, temporary_page);
+
+    // the P4 table shares all entries with the kernel, except the first one
+    {
+        let table = temporary_page.map_table_frame(p4_frame.clone(), active_table);
+        let kernel: &Table<Level4> = active_table.p4();
+        for index in 1..511 {
+            if let Some(frame) = kernel[index].pointed_frame() {
+                table[index].set(frame, kernel[index].flags());
+            }
+        }
+        table[0].set(p3_frame.clone(), PRESENT | WRITABLE);
+    }
+    temporary_page.unmap(active_table);
+
+    // the P3 table of the first entry shares everything except the heap and the stacks
+    {
+        let table = temporary_page.map_table_frame(p3_frame, active_table);
+        table.zero();
+        let kernel = active_table
+            .p4()
+            .next_table(0)
+            .expect("kernel has no P3 table");
+        for index in (0..512).filter(|index| !is_private(*index)) {
+            if let Some(frame) = kernel[index].pointed_frame() {
+                table[index].set(frame, kernel[index].flags());
+            }
+        }
+    }
+    temporary_page.unmap(active_table);
+
+    // the pages of the stack area get own P2 and P1 tables
+    active_table.with(&mut new_table, temporary_page, |mapper| {
+        for &(page, frame, user) in pages {
+            let page = Page::containing_address(page);
+            mapper.map_to(
+                page,
+                Frame::containing_address(frame),
+                WRITABLE,
+                &mut *frame_allocator,
+            );
+            if user {
+                mapper.set_user_accessible(page);
+            }
+        }
+    });
+
+    Some(AddressSpace {
+        p4: p4_frame.start_address(),
+    })
+}
+
+/// Frees the tables of an address space. The mapped frames are not freed, they belong to the
+/// kernel. The address space must not be active.
+///
+/// # Arguments
+/// * `active_table` - (&mut ActivePageTable) The kernel table.
+/// * `temporary_page` - (&mut TemporaryPage) Used to read the tables.
+/// * `frame_allocator` - (&mut A) Allocator which gets the frames of the tables.
+/// * `space` - (AddressSpace) The address space.
+pub fn free<A: FrameAllocator>(
+    active_table: &mut ActivePageTable,
+    temporary_page: &mut TemporaryPage,
+    frame_allocator: &mut A,
+    space: AddressSpace,
+) {
+    let p4_frame = Frame::containing_address(space.p4);
+    let mut table = InactivePageTable {
+        p4_frame: p4_frame.clone(),
+    };
+    let stacks = Page::containing_address(STACK_AREA_START).p3_index();
+    active_table.with(&mut table, temporary_page, |mapper| {
+        let p3 = mapper
+            .p4()
+            .next_table(0)
+            .expect("address space has no P3 table");
+        if let Some(p2) = p3.next_table(stacks) {
+            for index in 0..512 {
+                if let Some(frame) = p2[index].pointed_frame() {
+                    frame_allocator.deallocate_frame(frame);
+                }
+            }
+        }
+        if let Some(frame) = p3[stacks].pointed_frame() {
+            frame_allocator.deallocate_frame(frame);
+        }
+        let p3_frame = mapper.p4()[0]
+            .pointed_frame()
+            .expect("address space has no P3 table");
+        frame_allocator.deallocate_frame(p3_frame);
+    });
+    frame_allocator.deallocate_frame(p4_frame);
+}
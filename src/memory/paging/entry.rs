@@ -30,6 +30,11 @@ impl Entry {
         assert!(frame.start_address() & !0x000fffff_fffff000 == 0);
         self.0 = (frame.start_address() as u64) | flags.bits();
     }
+
+    /// Adds flags to a used entry, the frame and the other flags stay the same.
+    pub fn add_flags(&mut self, flags: EntryFlags) {
+        self.0 |= flags.bits();
+    }
 }
 
 bitflags! {
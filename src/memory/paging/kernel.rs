@@ -0,0 +1,131 @@
+//! Access rights of the kernel image.
+//! The bootloader maps the kernel writable and executable. `remap_the_kernel()` changes the
+//! mappings of the kernel pages according to its ELF file:
+//!
+//!     1. .text           -> read only, executable
+//!     2. .rodata         -> read only, not executable
+//!     3. .data and .bss  -> writable, not executable
+//!
+//! The section headers are not loaded by the bootloader, but the program headers are, because the
+//! linker places them in the first loaded segment. The linker groups the sections by their access
+//! rights into the segments, so the flags of the segments are used.
+//!
+//! The pages keep their frames and all other flags, e.g. `USER_ACCESSIBLE` of the user code.
+use super::{ActivePageTable, Page};
+use core::mem::size_of;
+use core::slice;
+
+/// Type of a program header which describes a loaded segment.
+const PT_LOAD: u32 = 1;
+/// Flag of an executable segment.
+const PF_X: u32 = 1;
+/// Flag of a writable segment.
+const PF_W: u32 = 2;
+
+/// Header of an ELF64 file.
+#[allow(dead_code)]
+#[repr(C)]
+struct ElfHeader {
+    ident: [u8; 16],
+    file_type: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    program_header_offset: u64,
+    section_header_offset: u64,
+    flags: u32,
+    header_size: u16,
+    program_header_size: u16,
+    program_header_count: u16,
+    section_header_size: u16,
+    section_header_count: u16,
+    section_names_index: u16,
+}
+
+/// Program header of an ELF64 file.
+#[allow(dead_code)]
+#[repr(C)]
+struct ProgramHeader {
+    segment_type: u32,
+    flags: u32,
+    offset: u64,
+    virtual_address: u64,
+    physical_address: u64,
+    file_size: u64,
+    memory_size: u64,
+    align: u64,
+}
+
+extern "C" {
+    /// ELF header of the kernel, defined by the linker.
+    static __ehdr_start: ElfHeader;
+}
+
+/// Enables the `NO_EXECUTE` flag of page table entries, it is reserved otherwise.
+pub fn enable_nxe_bit() {
+    use x86_64::registers::msr::{rdmsr, wrmsr, IA32_EFER};
+
+    let nxe_bit = 1 << 11;
+    unsafe {
+        let efer = rdmsr(IA32_EFER);
+        wrmsr(IA32_EFER, efer | nxe_bit);
+    }
+}
+
+/// Lets the cpu check the `WRITABLE` flag in ring 0 too, so the kernel can't overwrite its code or
+/// read only data.
+pub fn enable_write_protect_bit() {
+    use x86_64::registers::control_regs::{cr0, cr0_write, Cr0};
+
+    unsafe { cr0_write(cr0() | Cr0::WRITE_PROTECT) };
+}
+
+/// Changes the access rights of all pages of the kernel image to the flags of their segments. If
+/// two segments share a page, the page gets the rights of both. The NXE bit must be enabled.
+/// Called before the heap is initialized, so it must not allocate (e.g. no traces).
+///
+/// # Arguments
+/// * `active_table` - (&mut ActivePageTable) The kernel table.
+pub fn remap_the_kernel(active_table: &mut ActivePageTable) {
+    let header = unsafe { &__ehdr_start };
+    assert!(
+        header.ident[..4] == [0x7f, b'E', b'L', b'F'],
+        "kernel ELF header is not loaded"
+    );
+    assert!(header.program_header_size as usize == size_of::<ProgramHeader>());
+    let program_headers = unsafe {
+        slice::from_raw_parts(
+            (header as *const ElfHeader as usize + header.program_header_offset as usize)
+                as *const ProgramHeader,
+            header.program_header_count as usize,
+        )
+    };
+
+    // the last page of the previous segment and its rights
+    let mut previous: Option<(Page, bool, bool)> = None;
+    for segment in program_headers.iter() {
+        if segment.segment_type != PT_LOAD || segment.memory_size == 0 {
+            continue;
+        }
+        let start = segment.virtual_address as usize;
+        let end = start + segment.memory_size as usize;
+        let writable = segment.flags & PF_W != 0;
+        let executable = segment.flags & PF_X != 0;
+
+        let first = Page::containing_address(start);
+        let last = Page::containing_address(end - 1);
+        for page in Page::range_inclusive(first, last) {
+            match previous {
+                Some((shared, shared_writable, shared_executable)) if shared == page => {
+                    active_table.protect(
+                        page,
+                        writable || shared_writable,
+                        executable || shared_executable,
+                    );
+                }
+                _ => active_table.protect(page, writable, executable),
+            }
+        }
+        previous = Some((last, writable, executable));
+    }
+}
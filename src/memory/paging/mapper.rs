@@ -110,12 +110,67 @@ impl Mapper {
         self.map_to(page, frame, flags, allocator)
     }
 
+    /// Allows the access from ring 3 to a mapped page. The cpu checks the
+    /// `USER_ACCESSIBLE` flag on all four levels, so it is set in every entry of
+    /// the page. Other pages of the same tables stay private to the kernel.
+    pub fn set_user_accessible(&mut self, page: Page) {
+        use x86_64::instructions::tlb;
+        use x86_64::VirtualAddress;
+
+        let p4 = self.p4_mut();
+        p4[page.p4_index()].add_flags(USER_ACCESSIBLE);
+        let p3 = p4.next_table_mut(page.p4_index())
+            .expect("page is not mapped");
+        p3[page.p3_index()].add_flags(USER_ACCESSIBLE);
+        let p2 = p3.next_table_mut(page.p3_index())
+            .expect("mapping code does not support huge pages");
+        p2[page.p2_index()].add_flags(USER_ACCESSIBLE);
+        let p1 = p2.next_table_mut(page.p2_index())
+            .expect("mapping code does not support huge pages");
+        assert!(!p1[page.p1_index()].is_unused(), "page is not mapped");
+        p1[page.p1_index()].add_flags(USER_ACCESSIBLE);
+        tlb::flush(VirtualAddress(page.start_address()));
+    }
+
+    /// Changes the access rights of a mapped page, the other flags stay the same.
+    /// `NO_EXECUTE` must only be used if the NXE bit is enabled (see
+    /// `enable_nxe_bit()`).
+    pub fn protect(&mut self, page: Page, writable: bool, executable: bool) {
+        use x86_64::instructions::tlb;
+        use x86_64::VirtualAddress;
+
+        let p1 = self.p4_mut()
+            .next_table_mut(page.p4_index())
+            .and_then(|p3| p3.next_table_mut(page.p3_index()))
+            .and_then(|p2| p2.next_table_mut(page.p2_index()))
+            .expect("mapping code does not support huge pages");
+        let entry = &mut p1[page.p1_index()];
+        let frame = entry.pointed_frame().expect("page is not mapped");
+        let mut flags = entry.flags();
+        flags.remove(WRITABLE | NO_EXECUTE);
+        if writable {
+            flags.insert(WRITABLE);
+        }
+        if !executable {
+            flags.insert(NO_EXECUTE);
+        }
+        entry.set(frame, flags);
+        tlb::flush(VirtualAddress(page.start_address()));
+    }
+
     /// Unmaps the given page and adds all freed frames to the given
     /// `FrameAllocator`.
-    pub fn unmap<A>(&mut self, page: Page, _allocator: &mut A)
+    pub fn unmap<A>(&mut self, page: Page, allocator: &mut A)
     where
         A: FrameAllocator,
     {
+        let frame = self.unmap_frame(page);
+        allocator.deallocate_frame(frame);
+    }
+
+    /// Unmaps the given page without freeing its frame, e.g. because the frame
+    /// is still used by an other mapping. Returns the frame.
+    pub fn unmap_frame(&mut self, page: Page) -> Frame {
         use x86_64::instructions::tlb;
         use x86_64::VirtualAddress;
 
@@ -126,12 +181,12 @@ impl Mapper {
             .and_then(|p3| p3.next_table_mut(page.p3_index()))
             .and_then(|p2| p2.next_table_mut(page.p2_index()))
             .expect("mapping code does not support huge pages");
-        let _frame = p1[page.p1_index()]
+        let frame = p1[page.p1_index()]
             .pointed_frame()
             .expect("mapper unmap failed");
         p1[page.p1_index()].set_unused();
         tlb::flush(VirtualAddress(page.start_address()));
         // TODO free p(1,2,3) table if empty
-        //allocator.deallocate_frame(frame);
+        frame
     }
 }
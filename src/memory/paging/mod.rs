@@ -1,12 +1,16 @@
 //! Code of the `blog-os by phil oppermann`
+pub use self::address_space::AddressSpace;
 pub use self::entry::*;
+pub use self::kernel::{enable_nxe_bit, enable_write_protect_bit, remap_the_kernel};
 pub use self::mapper::Mapper;
-use self::temporary_page::TemporaryPage;
+pub use self::temporary_page::TemporaryPage;
 use core::ops::{Add, Deref, DerefMut};
 use memory::Frame;
 use memory::PAGE_SIZE;
 
+pub mod address_space;
 mod entry;
+mod kernel;
 mod mapper;
 mod table;
 mod temporary_page;
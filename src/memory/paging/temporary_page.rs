@@ -35,9 +35,10 @@ impl TemporaryPage {
         self.page.start_address()
     }
 
-    /// Unmaps the temporary page in the active table.
+    /// Unmaps the temporary page in the active table. The frame is not freed,
+    /// it still belongs to its owner.
     pub fn unmap(&mut self, active_table: &mut ActivePageTable) {
-        active_table.unmap(self.page, &mut self.allocator)
+        active_table.unmap_frame(self.page);
     }
 
     pub fn map_table_frame(
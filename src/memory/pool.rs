@@ -0,0 +1,131 @@
+//! Fixed-size block pools for real-time tasks.
+//! A pool allocates the memory of all its blocks at creation, afterwards allocating and freeing a
+//! block only pops or pushes the head of a free list. Both take a constant time, independent of
+//! the fragmentation of the heap, so they can be used by tasks which forbid the heap (see
+//! `tasks::forbid_heap()`).
+//!
+//! The free list is stored in the free blocks: the first word of a free block is the address of
+//! the next free block, `0` ends the list.
+use alloc::Vec;
+use core::mem::size_of;
+use core::ops::{Deref, DerefMut};
+use core::slice;
+use features::without_interrupts;
+use spin::Mutex;
+
+/// Free list of a pool.
+struct FreeList {
+    /// Address of the first free block, `0` if all blocks are used.
+    head: usize,
+}
+
+/// Pool of blocks with the same size. The blocks are aligned to 8 bytes.
+pub struct BlockPool {
+    /// Memory of all blocks. It is never resized, so the addresses of the blocks stay valid.
+    storage: Vec<u64>,
+    /// Size of a block in bytes.
+    block_size: usize,
+    /// Number of blocks.
+    count: usize,
+    /// Only locked with disabled interrupts.
+    free_list: Mutex<FreeList>,
+}
+
+impl BlockPool {
+    /// Creates a pool. The memory of the blocks is allocated from the heap, so pools should be
+    /// created during the initialization of a task.
+    ///
+    /// # Arguments
+    /// * `block_size` - (usize) Size of a block in bytes, rounded up to a multiple of 8.
+    /// * `count` - (usize) Number of blocks.
+    pub fn new(block_size: usize, count: usize) -> BlockPool {
+        assert!(block_size > 0 && count > 0, "empty block pool");
+        let words = (block_size + size_of::<u64>() - 1) / size_of::<u64>();
+        let mut storage: Vec<u64> = Vec::with_capacity(words * count);
+        storage.resize(words * count, 0);
+
+        // link all blocks, the first block is the head of the free list
+        let block_size = words * size_of::<u64>();
+        let start = storage.as_ptr() as usize;
+        for index in 0..count {
+            storage[index * words] = if index + 1 < count {
+                (start + (index + 1) * block_size) as u64
+            } else {
+                0
+            };
+        }
+
+        BlockPool {
+            storage,
+            block_size,
+            count,
+            free_list: Mutex::new(FreeList { head: start }),
+        }
+    }
+
+    /// Takes a free block in constant time. The block is returned to the pool when it is dropped.
+    /// It is not zeroed.
+    ///
+    /// # Return
+    /// * `Option<Block>` - The block, `None` if all blocks are used.
+    pub fn alloc(&self) -> Option<Block> {
+        let address = without_interrupts(|| {
+            let mut free_list = self.free_list.lock();
+            if free_list.head == 0 {
+                return None;
+            }
+            let address = free_list.head;
+            free_list.head = unsafe { *(address as *const usize) };
+            Some(address)
+        })?;
+        Some(Block {
+            pool: self,
+            address,
+        })
+    }
+
+    /// Pushes a block back to the free list in constant time.
+    fn free(&self, address: usize) {
+        let start = self.storage.as_ptr() as usize;
+        assert!(
+            address >= start
+                && address < start + self.count * self.block_size
+                && (address - start) % self.block_size == 0,
+            "block does not belong to the pool"
+        );
+        without_interrupts(|| {
+            let mut free_list = self.free_list.lock();
+            unsafe {
+                *(address as *mut usize) = free_list.head;
+            }
+            free_list.head = address;
+        });
+    }
+}
+
+/// Block of a `BlockPool`, which can be used as a byte slice.
+pub struct Block<'a> {
+    pool: &'a BlockPool,
+    address: usize,
+}
+
+impl<'a> Deref for Block<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.address as *const u8, self.pool.block_size) }
+    }
+}
+
+impl<'a> DerefMut for Block<'a> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.address as *mut u8, self.pool.block_size) }
+    }
+}
+
+impl<'a> Drop for Block<'a> {
+    /// Returns the block to its pool.
+    fn drop(&mut self) {
+        self.pool.free(self.address);
+    }
+}
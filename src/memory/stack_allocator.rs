@@ -1,63 +1,144 @@
-//! Code of the `blog-os by phil oppermann`
-use memory::paging::PageIter;
-use memory::paging::{self, ActivePageTable, Page};
-use memory::{FrameAllocator, PAGE_SIZE};
+//! Stack allocator for the tasks.
+//! The stacks are placed in a reserved virtual area (see `STACK_AREA_START`). Every stack gets an
+//! unmapped guard page below it, so a stack overflow causes a page fault instead of overwriting
+//! other memory. A fault in a guard page can be detected with `Stack::is_guard_page()`.
+//!
+//! The allocator only reserves the virtual pages, the `MemoryController` maps them to frames.
+//!
+//! The area is used from the bottom, the used part grows on demand. Freed stacks are unmapped
+//! and their pages (including the guard page) are kept as free slots. New stacks are placed in
+//! the first slot which is large enough, neighbouring slots are merged.
+//!
+//! New stacks are painted with `STACK_PAINT`. The part which still contains the pattern was never
+//! used, so `Stack::high_water_mark()` returns the maximum depth of the stack.
+use alloc::Vec;
+use core::mem::size_of;
+use memory::PAGE_SIZE;
+
+/// Pattern which is written to new stacks.
+const STACK_PAINT: u64 = 0x57ac_57ac_57ac_57ac;
+
+/// Free pages of the stack area.
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    /// Address of the first page.
+    start: usize,
+    /// Number of pages.
+    pages: usize,
+}
+
+impl Slot {
+    fn end(&self) -> usize {
+        self.start + self.pages * PAGE_SIZE
+    }
+}
 
 pub struct StackAllocator {
-    range: PageIter,
+    /// End of the stack area.
+    end: usize,
+    /// End of the used part of the area. The pages above are free.
+    next: usize,
+    /// Free slots below `next`, sorted by address.
+    free_slots: Vec<Slot>,
 }
 
 impl StackAllocator {
-    pub fn new(page_range: PageIter) -> StackAllocator {
-        StackAllocator { range: page_range }
+    /// Creates a stack allocator for the area from `start` to `end` (exclusive).
+    pub fn new(start: usize, end: usize) -> StackAllocator {
+        assert!(start % PAGE_SIZE == 0 && end % PAGE_SIZE == 0 && start < end);
+        StackAllocator {
+            end,
+            next: start,
+            free_slots: Vec::new(),
+        }
     }
 }
 
 impl StackAllocator {
-    pub fn alloc_stack<FA: FrameAllocator>(
-        &mut self,
-        active_table: &mut ActivePageTable,
-        frame_allocator: &mut FA,
-        size_in_pages: usize,
-    ) -> Option<Stack> {
+    /// Reserves the virtual pages of a stack and its guard page. The stack is not mapped, this
+    /// is done by the `MemoryController`.
+    pub fn alloc_stack(&mut self, size_in_pages: usize) -> Option<Stack> {
         if size_in_pages == 0 {
             return None; /* a zero sized stack makes no sense */
         }
 
-        // clone the range, since we only want to change it on success
-        let mut range = self.range.clone();
+        // the stack pages and a guard page
+        let pages = size_in_pages + 1;
+        let guard_page = match self.take_slot(pages) {
+            Some(start) => start,
+            None if self.next + pages * PAGE_SIZE <= self.end => {
+                let start = self.next;
+                self.next += pages * PAGE_SIZE;
+                start
+            }
+            None => return None, /* not enough pages */
+        };
+
+        let bottom = guard_page + PAGE_SIZE;
+        let top = guard_page + pages * PAGE_SIZE;
+        Some(Stack::new(top, bottom))
+    }
+
+    /// Releases the virtual pages of a stack, they are reused for new stacks. The stack must be
+    /// unmapped already.
+    pub fn free_stack(&mut self, stack: Stack) {
+        self.insert_slot(Slot {
+            start: stack.guard_page(),
+            pages: stack.size_in_pages() + 1,
+        });
+    }
 
-        // try to allocate the stack pages and a guard page
-        let guard_page = range.next();
-        let stack_start = range.next();
-        let stack_end = if size_in_pages == 1 {
-            stack_start
+    /// Takes the pages for a stack from the first free slot which is large enough.
+    ///
+    /// # Return
+    /// * `Option<usize>` - Address of the first page, `None` if no slot is large enough.
+    fn take_slot(&mut self, pages: usize) -> Option<usize> {
+        let index = self
+            .free_slots
+            .iter()
+            .position(|slot| slot.pages >= pages)?;
+        let start = self.free_slots[index].start;
+        if self.free_slots[index].pages == pages {
+            self.free_slots.remove(index);
         } else {
-            // choose the (size_in_pages-2)th element, since index
-            // starts at 0 and we already allocated the start page
-            range.nth(size_in_pages - 2)
-        };
+            self.free_slots[index].start += pages * PAGE_SIZE;
+            self.free_slots[index].pages -= pages;
+        }
+        Some(start)
+    }
 
-        match (guard_page, stack_start, stack_end) {
-            (Some(_), Some(start), Some(end)) => {
-                // success! write back updated range
-                self.range = range;
+    /// Adds a free slot and merges it with its neighbours. A slot at the end of the used part
+    /// shrinks the used part.
+    fn insert_slot(&mut self, slot: Slot) {
+        let index = self
+            .free_slots
+            .iter()
+            .position(|other| other.start > slot.start)
+            .unwrap_or(self.free_slots.len());
+        self.free_slots.insert(index, slot);
 
-                // map stack pages to physical frames
-                for page in Page::range_inclusive(start, end) {
-                    active_table.map(page, paging::WRITABLE, frame_allocator);
-                }
+        if index + 1 < self.free_slots.len()
+            && self.free_slots[index].end() == self.free_slots[index + 1].start
+        {
+            self.free_slots[index].pages += self.free_slots[index + 1].pages;
+            self.free_slots.remove(index + 1);
+        }
+        if index > 0 && self.free_slots[index - 1].end() == self.free_slots[index].start {
+            self.free_slots[index - 1].pages += self.free_slots[index].pages;
+            self.free_slots.remove(index);
+        }
 
-                // create a new stack
-                let top_of_stack = end.start_address() + PAGE_SIZE;
-                Some(Stack::new(top_of_stack, start.start_address()))
+        let last = self.free_slots.last().cloned();
+        if let Some(last) = last {
+            if last.end() == self.next {
+                self.next = last.start;
+                self.free_slots.pop();
             }
-            _ => None, /* not enough pages */
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Stack {
     top: usize,
     bottom: usize,
@@ -76,8 +157,106 @@ impl Stack {
         self.top
     }
 
-    #[allow(dead_code)]
     pub fn bottom(&self) -> usize {
         self.bottom
     }
+
+    pub fn size_in_pages(&self) -> usize {
+        (self.top - self.bottom) / PAGE_SIZE
+    }
+
+    /// Returns the address of the unmapped guard page below the stack.
+    pub fn guard_page(&self) -> usize {
+        self.bottom - PAGE_SIZE
+    }
+
+    /// Returns `true` if the address is in the guard page of the stack, e.g. the address of a
+    /// page fault which was caused by a stack overflow.
+    pub fn is_guard_page(&self, address: usize) -> bool {
+        address >= self.guard_page() && address < self.bottom
+    }
+
+    /// Returns the maximum number of bytes which were used on the stack. The stack must be mapped.
+    pub fn high_water_mark(&self) -> usize {
+        let mut address = self.bottom;
+        while address < self.top && unsafe { *(address as *const u64) } == STACK_PAINT {
+            address += size_of::<u64>();
+        }
+        self.top - address
+    }
+
+    /// Writes the `STACK_PAINT` pattern to the whole stack. The stack must be mapped.
+    pub unsafe fn paint(&self) {
+        let mut address = self.bottom;
+        while address < self.top {
+            *(address as *mut u64) = STACK_PAINT;
+            address += size_of::<u64>();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const START: usize = 0x10_0000;
+
+    fn allocator() -> StackAllocator {
+        StackAllocator::new(START, START + 16 * PAGE_SIZE)
+    }
+
+    #[test]
+    fn stacks_get_a_guard_page() {
+        let mut allocator = allocator();
+        let stack = allocator.alloc_stack(2).expect("no stack");
+        assert_eq!(stack.guard_page(), START);
+        assert_eq!(stack.bottom(), START + PAGE_SIZE);
+        assert_eq!(stack.top(), START + 3 * PAGE_SIZE);
+        assert_eq!(stack.size_in_pages(), 2);
+        assert!(stack.is_guard_page(START + 8));
+        assert!(!stack.is_guard_page(stack.bottom()));
+        assert!(allocator.alloc_stack(0).is_none());
+    }
+
+    #[test]
+    fn freed_slots_are_reused() {
+        let mut allocator = allocator();
+        let first = allocator.alloc_stack(3).expect("no stack");
+        let _second = allocator.alloc_stack(1).expect("no stack");
+        allocator.free_stack(first);
+        // the slot of the first stack is split
+        let third = allocator.alloc_stack(1).expect("no stack");
+        assert_eq!(third.guard_page(), START);
+        let fourth = allocator.alloc_stack(1).expect("no stack");
+        assert_eq!(fourth.guard_page(), START + 2 * PAGE_SIZE);
+    }
+
+    #[test]
+    fn neighbouring_slots_are_merged() {
+        let mut allocator = allocator();
+        let first = allocator.alloc_stack(1).expect("no stack");
+        let second = allocator.alloc_stack(1).expect("no stack");
+        let _third = allocator.alloc_stack(1).expect("no stack");
+        allocator.free_stack(second);
+        allocator.free_stack(first);
+        assert_eq!(allocator.free_slots.len(), 1);
+        let merged = allocator.alloc_stack(3).expect("no stack");
+        assert_eq!(merged.guard_page(), START);
+        assert!(allocator.free_slots.is_empty());
+    }
+
+    #[test]
+    fn freeing_the_last_stack_shrinks_the_used_part() {
+        let mut allocator = allocator();
+        let first = allocator.alloc_stack(1).expect("no stack");
+        let second = allocator.alloc_stack(1).expect("no stack");
+        allocator.free_stack(first);
+        allocator.free_stack(second);
+        assert_eq!(allocator.next, START);
+        assert!(allocator.free_slots.is_empty());
+        // the whole area can be used again
+        let stack = allocator.alloc_stack(15).expect("no stack");
+        assert_eq!(stack.top(), START + 16 * PAGE_SIZE);
+        assert!(allocator.alloc_stack(1).is_none());
+    }
 }
@@ -0,0 +1,210 @@
+//! Admission control for periodic tasks.
+//! Before a periodic task is added, the utilization of the whole task set is checked against the
+//! schedulability bound of the scheduling policy. Utilizations are stored as integers in parts per
+//! million (`UTILIZATION_SCALE`), because floating point numbers are not available in interrupts.
+//!
+//! For EDF the bound is 100%. For Rate Monotonic the Liu & Layland bound `n * (2^(1/n) - 1)` is
+//! used. Tasks with a deadline shorter than their period are accounted with `wcet / deadline`
+//! (density), which keeps the test sufficient for constrained deadlines.
+use features::without_interrupts;
+use spin::Mutex;
+
+/// Utilization of 100%.
+pub const UTILIZATION_SCALE: usize = 1_000_000;
+
+/// Liu & Layland bound for 1 to 10 tasks in parts per million. For more tasks `LIU_LAYLAND_LIMIT`
+/// (ln 2) is used.
+const LIU_LAYLAND: [usize; 10] = [
+    1_000_000, 828_427, 779_763, 756_828, 743_491, 734_772, 728_626, 724_061, 720_537, 717_734,
+];
+
+/// Limit of the Liu & Layland bound for an infinite number of tasks (ln 2).
+const LIU_LAYLAND_LIMIT: usize = 693_147;
+
+/// Schedulability bound which is used by the admission control.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UtilizationBound {
+    /// Earliest Deadline First, the task set is schedulable up to 100% utilization.
+    Edf,
+    /// Rate Monotonic, the task set is schedulable up to the Liu & Layland bound.
+    RateMonotonic,
+}
+
+/// Errors returned when a task is not admitted.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AdmissionError {
+    /// The parameters of the task are invalid, e.g. the period is `0` or the wcet is larger than
+    /// the deadline.
+    InvalidParameters,
+    /// The task set would not be schedulable anymore. Both values are in parts per million.
+    Overload {
+        /// Utilization of the task set including the new task.
+        utilization: usize,
+        /// Bound of the current scheduling policy.
+        bound: usize,
+    },
+}
+
+/// Utilization of all admitted periodic tasks.
+struct Reservation {
+    /// The bound which is currently used.
+    bound: UtilizationBound,
+    /// Sum of the utilization of all admitted tasks in parts per million.
+    utilization: usize,
+    /// Number of admitted periodic tasks.
+    tasks: usize,
+}
+
+/// Global reservation, guarded by a Mutex. It is also used by the scheduler, so it must only be
+/// locked with disabled interrupts.
+static RESERVATION: Mutex<Reservation> = Mutex::new(Reservation {
+    bound: UtilizationBound::Edf,
+    utilization: 0,
+    tasks: 0,
+});
+
+/// Computes the utilization of a task in parts per million.
+///
+/// # Arguments
+/// * `wcet` - (usize) Worst case execution time of a job.
+/// * `period` - (usize) Period of the task.
+/// * `deadline` - (usize) Relative deadline, `0` means the deadline equals the period.
+///
+/// # Return
+/// * `Option<usize>` - The utilization or `None` if the parameters are invalid.
+pub fn utilization(wcet: usize, period: usize, deadline: usize) -> Option<usize> {
+    let window = if deadline == 0 || deadline > period {
+        period
+    } else {
+        deadline
+    };
+    if window == 0 || wcet == 0 || wcet > window {
+        return None;
+    }
+    Some(((wcet as u64 * UTILIZATION_SCALE as u64) / window as u64) as usize)
+}
+
+/// Returns the utilization bound in parts per million for a task set with `tasks` tasks.
+fn bound_for(bound: UtilizationBound, tasks: usize) -> usize {
+    match bound {
+        UtilizationBound::Edf => UTILIZATION_SCALE,
+        UtilizationBound::RateMonotonic => {
+            if tasks == 0 {
+                UTILIZATION_SCALE
+            } else if tasks <= LIU_LAYLAND.len() {
+                LIU_LAYLAND[tasks - 1]
+            } else {
+                LIU_LAYLAND_LIMIT
+            }
+        }
+    }
+}
+
+/// Changes the bound which is used to admit new tasks. Already admitted tasks are not checked again.
+pub fn set_bound(bound: UtilizationBound) {
+    without_interrupts(|| RESERVATION.lock().bound = bound);
+}
+
+/// Checks if a periodic task can be added to the task set. If so, its utilization is reserved
+/// until `release()` is called.
+///
+/// # Arguments
+/// * `wcet` - (usize) Worst case execution time of a job.
+/// * `period` - (usize) Period of the task.
+/// * `deadline` - (usize) Relative deadline, `0` means the deadline equals the period.
+///
+/// # Return
+/// * `Result<(), AdmissionError>` - `Ok` if the task was admitted.
+pub fn admit(wcet: usize, period: usize, deadline: usize) -> Result<(), AdmissionError> {
+    let task_utilization = match utilization(wcet, period, deadline) {
+        Some(u) => u,
+        None => return Err(AdmissionError::InvalidParameters),
+    };
+    let result = without_interrupts(|| {
+        let mut reservation = RESERVATION.lock();
+        let new_utilization = reservation.utilization + task_utilization;
+        let bound = bound_for(reservation.bound, reservation.tasks + 1);
+        if new_utilization > bound {
+            return Err(AdmissionError::Overload {
+                utilization: new_utilization,
+                bound,
+            });
+        }
+        reservation.utilization = new_utilization;
+        reservation.tasks += 1;
+        Ok(())
+    });
+    // the trace is written after the reservation is unlocked, to keep the critical section short
+    if let Err(AdmissionError::Overload { utilization, bound }) = result {
+        trace_warn!(
+            "task rejected, utilization {} > bound {}",
+            utilization,
+            bound
+        );
+    }
+    result
+}
+
+/// Releases the utilization of a finished periodic task. Must be called with disabled interrupts,
+/// e.g. by the scheduler.
+///
+/// # Arguments
+/// * `wcet` - (usize) Worst case execution time of a job.
+/// * `period` - (usize) Period of the task.
+/// * `deadline` - (usize) Relative deadline.
+pub fn release(wcet: usize, period: usize, deadline: usize) {
+    if let Some(task_utilization) = utilization(wcet, period, deadline) {
+        let mut reservation = RESERVATION.lock();
+        reservation.utilization = reservation.utilization.saturating_sub(task_utilization);
+        reservation.tasks = reservation.tasks.saturating_sub(1);
+    }
+}
+
+/// Returns the utilization of all admitted tasks in parts per million.
+pub fn total_utilization() -> usize {
+    without_interrupts(|| RESERVATION.lock().utilization)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn utilization_uses_the_shorter_window() {
+        assert_eq!(utilization(1, 4, 0), Some(250_000));
+        assert_eq!(utilization(1, 10, 5), Some(200_000));
+        // a deadline after the period is accounted like an implicit deadline
+        assert_eq!(utilization(1, 4, 8), Some(250_000));
+        assert_eq!(utilization(4, 4, 0), Some(UTILIZATION_SCALE));
+    }
+
+    #[test]
+    fn utilization_rejects_invalid_parameters() {
+        assert_eq!(utilization(0, 4, 0), None);
+        assert_eq!(utilization(1, 0, 0), None);
+        assert_eq!(utilization(5, 4, 0), None);
+        assert_eq!(utilization(3, 10, 2), None);
+    }
+
+    #[test]
+    fn edf_bound_is_full_utilization() {
+        for tasks in 0..20 {
+            assert_eq!(bound_for(UtilizationBound::Edf, tasks), UTILIZATION_SCALE);
+        }
+    }
+
+    #[test]
+    fn rate_monotonic_bound_follows_liu_layland() {
+        let bound = UtilizationBound::RateMonotonic;
+        assert_eq!(bound_for(bound, 0), UTILIZATION_SCALE);
+        assert_eq!(bound_for(bound, 1), UTILIZATION_SCALE);
+        assert_eq!(bound_for(bound, 2), 828_427);
+        assert_eq!(bound_for(bound, 10), 717_734);
+        assert_eq!(bound_for(bound, 11), LIU_LAYLAND_LIMIT);
+        assert_eq!(bound_for(bound, 1000), LIU_LAYLAND_LIMIT);
+        // the bound decreases with every task
+        for tasks in 1..12 {
+            assert!(bound_for(bound, tasks + 1) <= bound_for(bound, tasks));
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! The cpu context of a task.
+//! On a timer interrupt the entry stub of the timer (see `interrupts::timer_entry`) pushes all
+//! general purpose registers onto the stack of the interrupted task. Together with the interrupt
+//! stack frame, which is pushed by the cpu, this forms the saved `Context` of the task. The
+//! scheduler only exchanges the stack pointer, the stub then restores all registers of the new
+//! task from its stack and returns with `iretq`.
+//!
+//! The stub also saves `cr3`, so every task continues in its own address space (see
+//! `memory::AddressSpace`). Tasks which are not isolated use the address space of the kernel.
+//!
+//! User tasks run in ring 3. The cpu switches to their kernel stack on an interrupt (see
+//! `interrupts::set_kernel_stack()`), so their context is saved on the kernel stack and the
+//! interrupt stack frame also contains the stack pointer and the segments of ring 3.
+//!
+//! The kernel is compiled without sse (see `x86_64-rtos.json`), so only the general purpose
+//! registers have to be saved.
+use core::mem::size_of;
+use x86_64::registers::control_regs;
+use x86_64::VirtualAddress;
+
+/// Interrupt flag and the reserved bit 1 of the `rflags` register. Used for new tasks, so they
+/// start with enabled interrupts.
+const INITIAL_FLAGS: u64 = 0x202;
+
+/// Saved registers of a task, as they are stored on the stack of the task. The order of the
+/// general purpose registers is the reversed push order of the timer entry stub, the last five
+/// fields are the interrupt stack frame.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Context {
+    /// Physical address of the P4 table of the task.
+    pub cr3: u64,
+    /// Keeps the stack 16 byte aligned for the call of the timer handler.
+    _reserved: u64,
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// Prepares the initial context of a new task at the top of its stack. When the task is scheduled
+/// for the first time, the timer entry stub *returns* to the entry function with all registers
+/// set to `0`. The task uses the current address space, which must be the one of the kernel.
+///
+/// # Arguments
+/// * `stack_top` - (VirtualAddress) Top address of the stack of the task. Must be mapped.
+/// * `entry` - (VirtualAddress) The function which is executed by the task.
+/// * `cpu_flags` - (u64) Additional cpu flags. The interrupts are always enabled.
+///
+/// # Return
+/// * `VirtualAddress` - The stack pointer of the saved context, stored in `TaskData`.
+pub unsafe fn init(
+    stack_top: VirtualAddress,
+    entry: VirtualAddress,
+    cpu_flags: u64,
+) -> VirtualAddress {
+    // the entry function expects a return address on the stack (rsp + 8 is 16 byte aligned)
+    let rsp = (stack_top.0 & !0xf) - size_of::<u64>();
+    let context = (rsp - size_of::<Context>()) as *mut Context;
+    *context = Context {
+        cr3: control_regs::cr3().0,
+        _reserved: 0,
+        r15: 0,
+        r14: 0,
+        r13: 0,
+        r12: 0,
+        r11: 0,
+        r10: 0,
+        r9: 0,
+        r8: 0,
+        rbp: 0,
+        rdi: 0,
+        rsi: 0,
+        rdx: 0,
+        rcx: 0,
+        rbx: 0,
+        rax: 0,
+        rip: entry.0 as u64,
+        cs: code_segment(),
+        rflags: cpu_flags | INITIAL_FLAGS,
+        rsp: rsp as u64,
+        ss: stack_segment(),
+    };
+    *(rsp as *mut u64) = 0;
+    VirtualAddress(context as usize)
+}
+
+/// Sets the first two arguments of the entry function of a new task. They are passed in `rdi` and
+/// `rsi` like the System V calling convention of `extern "C"` functions.
+///
+/// # Arguments
+/// * `stack_pointer` - (VirtualAddress) The stack pointer of the context, returned by `init()`.
+/// * `first` - (u64) First argument.
+/// * `second` - (u64) Second argument.
+pub unsafe fn set_arguments(stack_pointer: VirtualAddress, first: u64, second: u64) {
+    let context = &mut *(stack_pointer.0 as *mut Context);
+    context.rdi = first;
+    context.rsi = second;
+}
+
+/// Sets the address space of a new task, which is loaded when the task is scheduled. By default
+/// a new task uses the address space of the kernel.
+///
+/// # Arguments
+/// * `stack_pointer` - (VirtualAddress) The stack pointer of the context, returned by `init()`.
+/// * `p4_address` - (usize) Physical address of the P4 table (see `AddressSpace::p4_address()`).
+pub unsafe fn set_address_space(stack_pointer: VirtualAddress, p4_address: usize) {
+    let context = &mut *(stack_pointer.0 as *mut Context);
+    context.cr3 = p4_address as u64;
+}
+
+/// Lets a new task start in ring 3. The context must be prepared on the kernel stack of the task
+/// with `init()`, the task then starts at the same entry function on its user stack.
+///
+/// # Arguments
+/// * `stack_pointer` - (VirtualAddress) The stack pointer of the context, returned by `init()`.
+/// * `user_stack_top` - (VirtualAddress) Top address of the user stack. Must be mapped.
+/// * `code_segment` - (u64) Code segment selector of ring 3.
+/// * `stack_segment` - (u64) Stack segment selector of ring 3.
+pub unsafe fn set_user_mode(
+    stack_pointer: VirtualAddress,
+    user_stack_top: VirtualAddress,
+    code_segment: u64,
+    stack_segment: u64,
+) {
+    let context = &mut *(stack_pointer.0 as *mut Context);
+    let rsp = (user_stack_top.0 & !0xf) - size_of::<u64>();
+    *(rsp as *mut u64) = 0;
+    context.rsp = rsp as u64;
+    context.cs = code_segment;
+    context.ss = stack_segment;
+}
+
+/// Returns the saved context of a task.
+///
+/// # Arguments
+/// * `stack_pointer` - (VirtualAddress) The stack pointer of the saved context.
+pub unsafe fn get<'a>(stack_pointer: VirtualAddress) -> &'a Context {
+    &*(stack_pointer.0 as *const Context)
+}
+
+/// Returns the saved context of a task, e.g. to set the result of a syscall in `rax`.
+///
+/// # Arguments
+/// * `stack_pointer` - (VirtualAddress) The stack pointer of the saved context.
+pub unsafe fn get_mut<'a>(stack_pointer: VirtualAddress) -> &'a mut Context {
+    &mut *(stack_pointer.0 as *mut Context)
+}
+
+/// Reads the current code segment selector.
+fn code_segment() -> u64 {
+    let cs: u64;
+    unsafe {
+        asm!("mov $0, cs" : "=r"(cs) ::: "intel");
+    }
+    cs
+}
+
+/// Reads the current stack segment selector.
+fn stack_segment() -> u64 {
+    let ss: u64;
+    unsafe {
+        asm!("mov $0, ss" : "=r"(ss) ::: "intel");
+    }
+    ss
+}
@@ -0,0 +1,78 @@
+//! Detection of missed deadlines.
+//! On every call of the scheduler all jobs which are not completed (the task is ready or blocked)
+//! are checked against their absolute deadline. A job is only counted once, even if it runs for
+//! several periods after its deadline. What happens with a task which missed its deadline is
+//! defined by its `OverrunPolicy`.
+use features::without_interrupts;
+use spin::Mutex;
+use tasks::{TaskData, TaskStatus, KILLED};
+
+/// Defines what the scheduler does with a task which missed a deadline.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverrunPolicy {
+    /// The miss is only counted and traced, the job continues.
+    Continue,
+    /// The job continues, but the next job is skipped. The task sleeps one period longer.
+    SkipNextJob,
+    /// The task is killed and removed from the task list.
+    Kill,
+    /// The task with the given pid is woken up to handle the miss. It can get the task which
+    /// missed its deadline with `last_miss()`.
+    Handler(usize),
+}
+
+/// Information about a missed deadline.
+#[derive(Debug, Clone, Copy)]
+pub struct DeadlineMiss {
+    /// Pid of the task which missed its deadline.
+    pub pid: usize,
+    /// Name of the task which missed its deadline.
+    pub name: char,
+    /// The absolute deadline which was missed.
+    pub deadline: usize,
+    /// Timestamp at which the miss was detected.
+    pub detected: usize,
+}
+
+/// Stores the last missed deadline for handler tasks. Only locked with disabled interrupts.
+static LAST_MISS: Mutex<Option<DeadlineMiss>> = Mutex::new(None);
+
+/// Checks if the current job of a task missed its deadline. If so, the miss is counted and the
+/// overrun policy of the task is applied to the task itself. A handler task has to be woken up
+/// by the caller.
+///
+/// # Arguments
+/// * `task` - (&mut TaskData) The task to check.
+/// * `tsc` - (usize) The current timestamp.
+///
+/// # Return
+/// * `Option<DeadlineMiss>` - Information about the miss, `None` if the deadline was not missed.
+pub fn check(task: &mut TaskData, tsc: usize) -> Option<DeadlineMiss> {
+    let active = task.is_ready() || task.status == TaskStatus::BLOCKED;
+    if !active || task.job_missed || task.absolute_deadline >= tsc {
+        return None;
+    }
+    task.job_missed = true;
+    task.deadline_misses += 1;
+    match task.overrun_policy {
+        OverrunPolicy::Continue | OverrunPolicy::Handler(_) => {}
+        OverrunPolicy::SkipNextJob => task.skip_next_job = true,
+        OverrunPolicy::Kill => {
+            task.exit_code = KILLED;
+            task.status = TaskStatus::FINISHED;
+        }
+    }
+    let miss = DeadlineMiss {
+        pid: task.pid,
+        name: task.name,
+        deadline: task.absolute_deadline,
+        detected: tsc,
+    };
+    *LAST_MISS.lock() = Some(miss);
+    Some(miss)
+}
+
+/// Returns the last missed deadline. Used by handler tasks (see `OverrunPolicy::Handler`).
+pub fn last_miss() -> Option<DeadlineMiss> {
+    without_interrupts(|| *LAST_MISS.lock())
+}
@@ -0,0 +1,42 @@
+//! Earliest Deadline First scheduling.
+//! The ready task with the earliest absolute deadline runs. The absolute deadline is set each time
+//! a job is released (see `TaskData::release()`). Tasks without a deadline get `usize::MAX` and
+//! are scheduled round robin when no task with a deadline is ready.
+use scheduler::admission::UtilizationBound;
+use scheduler::{min_ready_by_key, Scheduler};
+use tasks::TaskData;
+
+/// EDF policy. It has no state, all information is stored in the `TaskData`.
+pub struct Edf;
+
+impl Scheduler for Edf {
+    fn name(&self) -> &'static str {
+        "EDF"
+    }
+
+    fn bound(&self) -> UtilizationBound {
+        UtilizationBound::Edf
+    }
+
+    /// The running task keeps the cpu if its deadline is strictly earlier, otherwise the task with
+    /// the earliest deadline is chosen.
+    fn pick_next(&mut self, tasks: &[TaskData], running: &TaskData) -> Option<usize> {
+        match min_ready_by_key(tasks, |task| task.absolute_deadline) {
+            Some(index)
+                if !running.is_ready()
+                    || tasks[index].absolute_deadline <= running.absolute_deadline =>
+            {
+                Some(index)
+            }
+            _ => None,
+        }
+    }
+
+    fn on_tick(&mut self, _running: &mut TaskData, _tsc: usize) {}
+
+    fn on_block(&mut self, _task: &TaskData) {}
+
+    fn on_wake(&mut self, _task: &mut TaskData, _tsc: usize) {}
+
+    fn on_exit(&mut self, _task: &TaskData) {}
+}
@@ -0,0 +1,73 @@
+//! Fixed priority preemptive scheduling.
+//! Every task has a priority (see `TaskData::priority`), which is given when the task is started,
+//! e.g. a RM or DM priority assignment. The ready task with the highest priority runs, tasks with
+//! the same priority share the cpu round robin: the task which waited the longest runs next.
+//!
+//! The policy keeps no ready queues, the order is derived from the `TaskData` like with the other
+//! policies. So the scheduler call does not allocate and changed priorities, e.g. by the
+//! protocols of the kernel `Mutex`, take effect without updating any queue.
+use scheduler::admission::UtilizationBound;
+use scheduler::{min_ready_by_key, Scheduler};
+use tasks::{TaskData, PRIORITY_LEVELS};
+
+/// Number of scheduler calls a task may run before the next task with the same priority is
+/// scheduled (about 25ms).
+const QUANTUM: usize = 3;
+
+/// Fixed priority policy.
+pub struct FixedPriority {
+    /// Number of scheduler calls since the running task got the cpu.
+    elapsed: usize,
+}
+
+impl FixedPriority {
+    pub fn new() -> Self {
+        FixedPriority { elapsed: 0 }
+    }
+}
+
+/// Returns the key of a task for `min_ready_by_key()`, a smaller key means a higher priority.
+fn key(task: &TaskData) -> usize {
+    PRIORITY_LEVELS - task.priority as usize
+}
+
+impl Scheduler for FixedPriority {
+    fn name(&self) -> &'static str {
+        "FP"
+    }
+
+    /// The Liu & Layland bound is only sufficient if the priorities follow a RM assignment.
+    fn bound(&self) -> UtilizationBound {
+        UtilizationBound::RateMonotonic
+    }
+
+    /// The running task is preempted by a task with a higher priority. A task with the same
+    /// priority only gets the cpu when the time slice of the running task is over.
+    fn pick_next(&mut self, tasks: &[TaskData], running: &TaskData) -> Option<usize> {
+        let index = min_ready_by_key(tasks, key)?;
+        if running.is_ready() {
+            let priority = tasks[index].priority;
+            if priority < running.priority
+                || (priority == running.priority && self.elapsed < QUANTUM)
+            {
+                return None;
+            }
+        }
+        self.elapsed = 0;
+        Some(index)
+    }
+
+    fn on_tick(&mut self, _running: &mut TaskData, _tsc: usize) {
+        self.elapsed += 1;
+    }
+
+    fn on_block(&mut self, _task: &TaskData) {
+        self.elapsed = 0;
+    }
+
+    fn on_wake(&mut self, _task: &mut TaskData, _tsc: usize) {}
+
+    fn on_exit(&mut self, _task: &TaskData) {
+        self.elapsed = 0;
+    }
+}
@@ -1,13 +1,67 @@
 //! This module stores all tasks and handles (schedules) all tasks.
-//! Currently this module only supports EDF scheduling.
+//! Every task carries a period, a relative deadline and a WCET budget (see `TaskData`). Each time a
+//! task is released (it is started or it wakes up from a sleep) a new job begins and its absolute
+//! deadline is set to `release_time + deadline`.
 //!
+//! Which task runs next is decided by a scheduling policy, which implements the trait `Scheduler`.
+//! The policy is selected at boot with `set_policy()`. Supported policies are:
+//!
+//!     1. EDF            -> Earliest Deadline First (default)
+//!     2. RateMonotonic  -> Fixed priorities, derived from the periods
+//!     3. FixedPriority  -> Fixed priorities given by the tasks, round robin on equal priorities
+//!     4. RoundRobin     -> Time slices for all ready tasks
+//!     5. Sleep          -> The first policy of the system, based on the sleep time of the tasks
+//!
+//! Tasks which wait for a kernel object (see `sync`) are `BLOCKED`. They are not scheduled until
+//! they are woken up with `unblock()` or their timeout is reached.
+//!
+use self::admission::UtilizationBound;
+use self::deadline::{DeadlineMiss, OverrunPolicy};
+use self::edf::Edf;
+use self::fixed_priority::FixedPriority;
+use self::rate_monotonic::RateMonotonic;
+use self::round_robin::RoundRobin;
+use self::sleep::Sleep;
+use alloc::boxed::Box;
 use alloc::Vec;
-use memory::MemoryController;
+use core::usize;
+use features::{ms_to_ticks, without_interrupts};
+use interrupts;
+use memory::{self, AddressSpace, MemoryController, Stack};
 use spin::Mutex;
+use sync::PriorityBoosts;
 use tasks::*;
 use x86_64;
 use x86_64::instructions::rdtsc;
-use x86_64::structures::idt::ExceptionStackFrame;
+use x86_64::VirtualAddress;
+use HEAP_ALLOCATOR;
+
+pub mod admission;
+pub mod context;
+pub mod deadline;
+mod edf;
+mod fixed_priority;
+mod rate_monotonic;
+mod round_robin;
+mod sleep;
+pub mod srp;
+pub mod timer;
+
+/// A task which was removed by the scheduler.
+struct FinishedTask {
+    pid: usize,
+    exit_code: isize,
+    /// Stack of the task, freed by the main task.
+    stack: Option<Stack>,
+    /// Address space of an isolated task, freed by the main task.
+    address_space: Option<AddressSpace>,
+    /// Kernel stack of a user task, freed by the main task.
+    kernel_stack: Option<Stack>,
+    /// Kernel mutexes which the task still held, unlocked after the task lists are unlocked.
+    held_mutexes: PriorityBoosts,
+    /// Number of stack resources which the task still held, released by the scheduler.
+    held_resources: usize,
+}
 
 /// Global variable with information about the current task.
 /// Used, inter alia, to remember the sleep ticks for the scheduler.
@@ -22,12 +76,258 @@ pub static mut RUNNING_TASK: Mutex<TaskData> = Mutex::new(TaskData {
     time_sleep: 1,
     time_active: 1,
     last_time_stamp: 1,
+    period: 0,
+    deadline: 0,
+    wcet: 0,
+    release_time: 0,
+    absolute_deadline: usize::MAX,
+    deadline_misses: 0,
+    job_missed: false,
+    skip_next_job: false,
+    overrun_policy: OverrunPolicy::Continue,
+    priority: DEFAULT_PRIORITY,
+    base_priority: DEFAULT_PRIORITY,
+    boosts: PriorityBoosts::new(),
+    preemption_level: DEFAULT_PRIORITY,
+    block_timeout: usize::MAX,
+    timed_out: false,
+    exit_code: 0,
+    stack: None,
+    heap_forbidden: false,
+    address_space: None,
+    kernel_stack: None,
 });
 
 lazy_static! {
-    /// Global vector which stores all current tasks.
-    /// All tasks are sorted as follows: IDLE -> max sleep -> min sleep -> READY
+    /// Global vector which stores all current tasks except the running one (see `RUNNING_TASK`).
+    /// The order of the tasks has no meaning, the scheduler searches the whole vector.
     pub static ref TASKS: Mutex<Vec<TaskData>> = Mutex::new(vec![]);
+
+    /// The scheduling policy which is used by `schedule()`. Only locked with disabled interrupts.
+    static ref POLICY: Mutex<Box<Scheduler + Send>> = Mutex::new(Box::new(Edf));
+
+    /// Buffers of `schedule()`. Only locked with disabled interrupts.
+    static ref BUFFERS: Mutex<ScheduleBuffers> = Mutex::new(ScheduleBuffers {
+        misses: Vec::new(),
+        finished: Vec::new(),
+        handlers: Vec::new(),
+    });
+}
+
+/// Buffers which `schedule()` fills on every call. They are kept between the calls and their
+/// capacity is reserved when a task is added (see `add_task()`), so the scheduler does not
+/// allocate in the timer interrupt.
+struct ScheduleBuffers {
+    misses: Vec<DeadlineMiss>,
+    finished: Vec<FinishedTask>,
+    /// Pids of the handler tasks which are woken up because of a missed deadline.
+    handlers: Vec<usize>,
+}
+
+impl ScheduleBuffers {
+    /// Makes sure that every buffer can hold an entry for each task. The buffers are empty between
+    /// the calls of `schedule()`.
+    ///
+    /// # Arguments
+    /// * `tasks` - (usize) Number of tasks including the running one.
+    fn reserve(&mut self, tasks: usize) {
+        self.misses.reserve(tasks);
+        self.finished.reserve(tasks);
+        self.handlers.reserve(tasks);
+    }
+}
+
+/// Interface of a scheduling policy. The scheduler calls the functions of the policy, the policy
+/// itself never changes the task lists. All functions are called inside the timer interrupt.
+pub trait Scheduler {
+    /// Name of the policy, used for traces.
+    fn name(&self) -> &'static str;
+
+    /// Utilization bound which is used by the admission control for this policy.
+    fn bound(&self) -> UtilizationBound;
+
+    /// Chooses the task which runs next.
+    ///
+    /// # Arguments
+    /// * `tasks` - (&[TaskData]) All tasks except the running one. Only tasks which are ready
+    /// (see `TaskData::is_ready()`) may be chosen.
+    /// * `running` - (&TaskData) The running task. It may not be ready anymore, e.g. if it sleeps.
+    ///
+    /// # Return
+    /// * `Option<usize>` - Index of the next task in `tasks`. `None` keeps the running task, or
+    /// schedules the idle task if the running task is not ready.
+    fn pick_next(&mut self, tasks: &[TaskData], running: &TaskData) -> Option<usize>;
+
+    /// Called on every call of the scheduler with the running task.
+    fn on_tick(&mut self, running: &mut TaskData, tsc: usize);
+
+    /// Called when the running task stops being ready, e.g. because it sleeps or is blocked.
+    fn on_block(&mut self, task: &TaskData);
+
+    /// Called when a task is woken up and a new job is released, or when a blocked task is woken
+    /// up and continues its job (see `unblock()`).
+    fn on_wake(&mut self, task: &mut TaskData, tsc: usize);
+
+    /// Called when a task is finished and removed from the task list.
+    fn on_exit(&mut self, task: &TaskData);
+}
+
+/// The scheduling policies which can be selected with `set_policy()`.
+// only the policy of `SCHEDULING_POLICY` in `main.rs` is constructed in a build
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    /// Earliest Deadline First.
+    Edf,
+    /// Fixed priority Rate Monotonic.
+    RateMonotonic,
+    /// Fixed priority with the priorities given by the tasks.
+    FixedPriority,
+    /// Round robin.
+    RoundRobin,
+    /// The first policy of the system, based on the sleep time of the tasks.
+    Sleep,
+}
+
+/// Selects the scheduling policy. Should be called at boot, before `sched_init()`, because the
+/// admission control uses the bound of the policy.
+///
+/// # Arguments
+/// * `policy` - (Policy) The new scheduling policy.
+pub fn set_policy(policy: Policy) {
+    let scheduler: Box<Scheduler + Send> = match policy {
+        Policy::Edf => Box::new(Edf),
+        Policy::RateMonotonic => Box::new(RateMonotonic),
+        Policy::FixedPriority => Box::new(FixedPriority::new()),
+        Policy::RoundRobin => Box::new(RoundRobin::new()),
+        Policy::Sleep => Box::new(Sleep),
+    };
+    admission::set_bound(scheduler.bound());
+    trace_info!("scheduling policy: {}", scheduler.name());
+    without_interrupts(|| *POLICY.lock() = scheduler);
+}
+
+/// Returns the index of the ready task with the smallest key. On equal keys the task which waited
+/// the longest is chosen. Used by the scheduling policies. Tasks which may not preempt because of
+/// the system ceiling of the Stack Resource Policy (see `srp`) are skipped.
+///
+/// # Arguments
+/// * `tasks` - (&[TaskData]) All tasks except the running one.
+/// * `key` - (Fn(&TaskData) -> usize) Computes the key of a task.
+pub fn min_ready_by_key<F>(tasks: &[TaskData], key: F) -> Option<usize>
+where
+    F: Fn(&TaskData) -> usize,
+{
+    let ceiling = srp::system_ceiling();
+    let mut best: Option<(usize, usize, usize)> = None;
+    for (i, task) in tasks.iter().enumerate() {
+        if !task.is_ready() || !ceiling.allows(task) {
+            continue;
+        }
+        let candidate = (key(task), task.last_time_stamp, i);
+        best = match best {
+            Some(b) if (b.0, b.1) <= (candidate.0, candidate.1) => Some(b),
+            _ => Some(candidate),
+        };
+    }
+    best.map(|b| b.2)
+}
+
+/// Returns the pid of the running task.
+pub fn current_pid() -> usize {
+    without_interrupts(|| unsafe { RUNNING_TASK.lock().pid })
+}
+
+/// Runs `f` with the `TaskData` of a task, which can be the running task or any task in `TASKS`.
+/// Used to change the priority of a task, e.g. by the protocols of the kernel `Mutex`.
+///
+/// # Arguments
+/// * `pid` - (usize) Pid of the task.
+/// * `f` - (FnOnce(&mut TaskData) -> R) Called with disabled interrupts, must not block.
+///
+/// # Return
+/// * `Option<R>` - The result of `f`, `None` if there is no task with this pid.
+pub fn with_task<F, R>(pid: usize, f: F) -> Option<R>
+where
+    F: FnOnce(&mut TaskData) -> R,
+{
+    without_interrupts(|| {
+        let mut tasks = TASKS.lock();
+        let mut running = unsafe { RUNNING_TASK.lock() };
+        if running.pid == pid {
+            return Some(f(&mut *running));
+        }
+        tasks.iter_mut().find(|task| task.pid == pid).map(f)
+    })
+}
+
+/// Blocks the running task and calls the scheduler. The task is not scheduled until it is woken up
+/// with `unblock()`.
+/// Must be called with disabled interrupts, after the task was added to a wait queue. Otherwise a
+/// wake up between both steps would be lost. No spin lock may be held by the caller.
+pub fn block_current() {
+    block_current_until(usize::MAX);
+}
+
+/// Blocks the running task like `block_current()`, but wakes it up at the latest when the
+/// timestamp `timeout` is reached. A task which timed out has to remove itself from the wait
+/// queue.
+///
+/// # Arguments
+/// * `timeout` - (usize) Timestamp at which the task is woken up, `usize::MAX` for no timeout.
+///
+/// # Return
+/// * `bool` - `true` if the task was woken up by `unblock()`, `false` if it timed out.
+pub fn block_current_until(timeout: usize) -> bool {
+    unsafe {
+        {
+            let mut running = RUNNING_TASK.lock();
+            running.status = TaskStatus::BLOCKED;
+            running.block_timeout = timeout;
+            running.timed_out = false;
+        }
+        int!(0x20);
+        !RUNNING_TASK.lock().timed_out
+    }
+}
+
+/// Wakes up a blocked task. The task continues its current job, so no new job is released.
+///
+/// # Arguments
+/// * `pid` - (usize) Pid of the blocked task.
+///
+/// # Return
+/// * `bool` - `false` if there is no blocked task with this pid, e.g. because it was killed.
+pub fn unblock(pid: usize) -> bool {
+    let tsc = rdtsc() as usize;
+    without_interrupts(|| {
+        let mut tasks = TASKS.lock();
+        let mut running = unsafe { RUNNING_TASK.lock() };
+        let mut policy = POLICY.lock();
+        // the running task is only blocked while the scheduler is called, e.g. by a timer callback
+        let task = if running.pid == pid {
+            Some(&mut *running)
+        } else {
+            tasks.iter_mut().find(|task| task.pid == pid)
+        };
+        match task {
+            Some(ref mut task) if task.status == TaskStatus::BLOCKED => {
+                task.status = TaskStatus::RUNNING;
+                task.block_timeout = usize::MAX;
+                policy.on_wake(task, tsc);
+                true
+            }
+            _ => false,
+        }
+    })
+}
+
+/// Calls the scheduler immediately, e.g. after a task was woken up which may preempt the running
+/// task.
+pub fn reschedule() {
+    unsafe {
+        int!(0x20);
+    }
 }
 
 /// Used to initialize tasks.
@@ -37,68 +337,89 @@ lazy_static! {
 /// Tasks are inserted with TaskStatus `READY` (excluding the idle task, which always has the
 /// TaskStatus `IDLE`)
 ///
+/// The clocks, the keyboard task and `htop` are periodic tasks and get a relative deadline and a
+/// WCET budget. They are checked by the admission control (see `admission`). The shell is
+/// aperiodic and only runs when no task with a deadline is ready. For the fixed priority policy
+/// the tasks get deadline monotonic priorities above the default priority of the aperiodic tasks
+/// (keyboard 20 ms -> 4, clocks 100 ms -> 3, htop 500 ms -> 2), which are also used as preemption
+/// levels (see `srp`).
+///
 /// # Arguments
 /// * `memory_controller` - (MemoryController) Used to allocate memory.
 ///
 pub fn sched_init(memory_controller: &mut MemoryController) {
     let memory = memory_controller.alloc_stack(3).expect("Ooopsie");
-    TASKS.lock().insert(
-        0,
-        TaskData::new(
+    insert_task(
+        TaskData::new_periodic(
             '1',
             0,
             x86_64::VirtualAddress(memory.top()),
             x86_64::VirtualAddress(uptime1 as usize),
-            TaskStatus::READY,
+            ms_to_ticks(1000),
+            ms_to_ticks(100),
+            ms_to_ticks(5),
         ),
+        memory,
+        3,
     );
     let memory = memory_controller.alloc_stack(5).expect("Ooopsie");
-    TASKS.lock().insert(
-        0,
-        TaskData::new(
+    insert_task(
+        TaskData::new_periodic(
             '2',
             0,
             x86_64::VirtualAddress(memory.top()),
             x86_64::VirtualAddress(uptime2 as usize),
-            TaskStatus::READY,
+            ms_to_ticks(1000),
+            ms_to_ticks(100),
+            ms_to_ticks(5),
         ),
+        memory,
+        3,
     );
     let memory = memory_controller.alloc_stack(3).expect("Ooopsie");
-    TASKS.lock().insert(
-        0,
-        TaskData::new(
+    insert_task(
+        TaskData::new_periodic(
             '3',
             0,
             x86_64::VirtualAddress(memory.top()),
             x86_64::VirtualAddress(uptime3 as usize),
-            TaskStatus::READY,
+            ms_to_ticks(1000),
+            ms_to_ticks(100),
+            ms_to_ticks(5),
         ),
+        memory,
+        3,
     );
     let memory = memory_controller.alloc_stack(3).expect("Ooopsie");
-    TASKS.lock().insert(
-        0,
-        TaskData::new(
+    insert_task(
+        TaskData::new_periodic(
             '4',
             0,
             x86_64::VirtualAddress(memory.top()),
             x86_64::VirtualAddress(uptime4 as usize),
-            TaskStatus::READY,
+            ms_to_ticks(1000),
+            ms_to_ticks(100),
+            ms_to_ticks(5),
         ),
+        memory,
+        3,
     );
     let memory = memory_controller.alloc_stack(3).expect("Ooopsie");
-    TASKS.lock().insert(
-        0,
-        TaskData::new(
+    insert_task(
+        TaskData::new_periodic(
             'k',
             0,
             x86_64::VirtualAddress(memory.top()),
             x86_64::VirtualAddress(task_keyboard as usize),
-            TaskStatus::READY,
+            ms_to_ticks(20),
+            ms_to_ticks(20),
+            ms_to_ticks(1),
         ),
+        memory,
+        4,
     );
     let memory = memory_controller.alloc_stack(4).expect("Ooopsie");
-    TASKS.lock().insert(
-        0,
+    insert_task(
         TaskData::new(
             's',
             0,
@@ -106,10 +427,11 @@ pub fn sched_init(memory_controller: &mut MemoryController) {
             x86_64::VirtualAddress(shell as usize),
             TaskStatus::READY,
         ),
+        memory,
+        DEFAULT_PRIORITY,
     );
     let memory = memory_controller.alloc_stack(2).expect("Ooopsie");
-    TASKS.lock().insert(
-        0,
+    insert_task(
         TaskData::new(
             'i',
             0,
@@ -117,125 +439,333 @@ pub fn sched_init(memory_controller: &mut MemoryController) {
             x86_64::VirtualAddress(idle_task as usize),
             TaskStatus::IDLE,
         ),
+        memory,
+        IDLE_PRIORITY,
     );
     let memory = memory_controller.alloc_stack(3).expect("Ooopsie");
-    TASKS.lock().insert(
-        0,
-        TaskData::new(
+    insert_task(
+        TaskData::new_periodic(
             'h',
             0,
             x86_64::VirtualAddress(memory.top()),
             x86_64::VirtualAddress(htop as usize),
-            TaskStatus::READY,
+            ms_to_ticks(1000),
+            ms_to_ticks(500),
+            ms_to_ticks(10),
         ),
+        memory,
+        2,
     );
     trace_info!("initialised scheduler");
 }
 
+/// Inserts a task created by `sched_init()` into the `TASKS` vector. Periodic tasks are checked by
+/// the admission control, the initial task set has to be schedulable.
+fn insert_task(mut task: TaskData, stack: Stack, priority: u8) {
+    task.stack = Some(stack);
+    task.set_base_priority(priority);
+    task.preemption_level = priority;
+    if task.period != 0 {
+        admission::admit(task.wcet, task.period, task.deadline)
+            .expect("initial task set is not schedulable");
+    }
+    add_task(task);
+}
+
+/// Adds a started task to `TASKS`. Room for the task is reserved in the buffers of `schedule()`,
+/// so the scheduler does not allocate in the timer interrupt. Must be called with disabled
+/// interrupts.
+///
+/// # Arguments
+/// * `task` - (TaskData) The new task.
+pub fn add_task(task: TaskData) {
+    let mut tasks = TASKS.lock();
+    tasks.push(task);
+    // the running task is not in `TASKS`
+    BUFFERS.lock().reserve(tasks.len() + 1);
+}
+
 /// Used to schedule all tasks.
-/// Therefore the function saves the `cpu_flags`, `stack_pointer` and `instruction_pointer` given by
-/// the timer interrupt. The choice for the next task is separated in three parts:
+/// Therefore the function saves the `stack_pointer` given by the timer interrupt. The registers of
+/// the task are saved on its stack (see `context`). The software timers are checked before the
+/// tasks (see `timer`). First all jobs which are not completed are checked for a missed deadline
+/// (see `deadline`). The choice for the next task is separated in three parts:
 ///
-/// 1.) There is a `READY` Task in the `TASKS` vector -> schedule this task next.
+/// 1.) All `SLEEPING` tasks whose `sleep_ticks` are smaller then the current timestamp_counter
+/// are woken up. Waking up releases a new job, which gets a new absolute deadline.
 ///
-/// 2.) Else, the top task `sleep_ticks` are smaller then the current timestamp_counter -> schedule
+/// 2.) The scheduling policy (see `Scheduler`) chooses the next task out of all ready tasks
+/// (`READY` or `RUNNING`) or keeps the running task.
 ///
 /// 3.) Else, no task is ready to run -> schedule `Idle` task, respectively, keep `Idle` as running
 /// if `Idle` was the last running task.
 ///
 ///
 /// # Arguments
-/// * `f` - (ExceptionStackFrame) Stores the data which are given by an interrupt, in this case by
-/// a timer interrupt. The `ExceptionStackFrame` includes the `cpu_flags`, `stack_pointer`,
-/// `instruction_pointer` and some other data which is not used by the scheduler.
+/// * `stack_pointer` - (VirtualAddress) Stack pointer of the interrupted task, pointing to its
+/// saved `Context`.
 ///
-pub fn schedule(f: &mut ExceptionStackFrame) {
+/// # Return
+/// * `VirtualAddress` - Stack pointer of the task which runs next. The timer entry stub restores
+/// the registers from this stack.
+pub fn schedule(stack_pointer: VirtualAddress) -> VirtualAddress {
     //early_trace!();
-    let cpuflags = f.cpu_flags;
-    let stackpointer = f.stack_pointer;
-    let instructionpointer = f.instruction_pointer;
-    // check if a task is ready to run
-    let tsc = rdtsc();
-    let to_run = if TASKS.lock().last().expect("last").status == TaskStatus::READY {
-        trace_debug!("popped ready task");
-        let x = TASKS.lock().pop().expect("popped");
-        x
-    } else if TASKS.lock().last().expect("tadaa").sleep_ticks < tsc as usize {
-        let x = TASKS.lock().pop().expect("popped");
-        trace_debug!("popped after sleep task {}", x.sleep_ticks);
-        x
-    } else if unsafe { RUNNING_TASK.lock().status == TaskStatus::IDLE } {
-        //early_trace!("do nothing");
-        return;
-    } else {
-        trace_debug!("popped idle");
-        let x = TASKS.lock().remove(0);
-        x
+    let tsc = rdtsc() as usize;
+    // the scheduler needs the heap, even if the interrupted task forbids it
+    memory::set_heap_forbidden(false);
+    memory::set_heap_owner(0);
+    // callbacks can wake up tasks, so the timers are checked before the task lists are locked
+    timer::expire(tsc);
+    let mut buffers = BUFFERS.lock();
+    let ScheduleBuffers {
+        ref mut misses,
+        ref mut finished,
+        ref mut handlers,
+    } = *buffers;
+    let mut stack_pointer = stack_pointer;
+    let (scheduled, running_pid, heap_forbidden, kernel_stack) = {
+        let mut tasks = TASKS.lock();
+        let mut running = unsafe { RUNNING_TASK.lock() };
+        let mut policy = POLICY.lock();
+
+        policy.on_tick(&mut running, tsc);
+        check_deadlines(
+            &mut **policy,
+            &mut tasks,
+            &mut running,
+            tsc,
+            misses,
+            handlers,
+        );
+        remove_finished(&mut **policy, &mut tasks, finished);
+        for task in tasks.iter_mut() {
+            wake_up(&mut **policy, task, tsc);
+        }
+        wake_up(&mut **policy, &mut running, tsc);
+
+        let scheduled = switch_task(
+            &mut **policy,
+            &mut stack_pointer,
+            tsc,
+            &mut tasks,
+            &mut running,
+            finished,
+        );
+        let kernel_stack = running.kernel_stack.as_ref().map(|stack| stack.top());
+        (scheduled, running.pid, running.heap_forbidden, kernel_stack)
     };
-    //let to_run = TASKS.lock().pop().expect("scheduler schedule failed");
-    //trace!("task: {}", to_run.instruction_pointer);
 
-    unsafe {
-        let not_finished = RUNNING_TASK.lock().status != TaskStatus::FINISHED;
-        if not_finished {
-            let name_c = RUNNING_TASK.lock().name;
-            let pid_c = RUNNING_TASK.lock().pid;
-            let sleep_ticks_c = RUNNING_TASK.lock().sleep_ticks;
-            let time_sleep_c = RUNNING_TASK.lock().time_sleep;
-            let last_time_stamp_c = RUNNING_TASK.lock().last_time_stamp;
-            // PID = 0 --> main function
-            //let old = TaskData::new(cpuflags, stackpointer, instructionpointer, to_run.status);
-            let new_status = if RUNNING_TASK.lock().status == TaskStatus::IDLE {
-                TaskStatus::IDLE
-            } else {
-                TaskStatus::RUNNING
-            };
-            let time_active_c = if (tsc as usize) < last_time_stamp_c {
-                0
-            } else {
-                tsc as usize - last_time_stamp_c
-            };
-            let old = TaskData::copy(
-                name_c,
-                pid_c,
-                cpuflags,
-                stackpointer,
-                instructionpointer,
-                new_status,
-                sleep_ticks_c,
-                time_sleep_c,
-                time_active_c,
-                tsc as usize,
+    // the mutexes and handles are released after the task lists are unlocked, so waiting and
+    // joining tasks can be woken up
+    for task in finished.drain(..) {
+        task.held_mutexes.unlock_all(task.pid);
+        exited(task.pid, task.exit_code);
+        if task.held_mutexes.count() > 0 || task.held_resources > 0 {
+            trace_warn!(
+                "task {} exited with {} kernel mutexes and {} stack resources, released them",
+                task.pid,
+                task.held_mutexes.count(),
+                task.held_resources
             );
-            let mut position = 0;
-            if old.status == TaskStatus::IDLE {
-                TASKS.lock().insert(position, old);
-            } else {
-                for task in TASKS.lock().iter() {
-                    if task.sleep_ticks <= sleep_ticks_c && task.status != TaskStatus::IDLE {
-                        break;
-                    }
-                    position += 1;
-                }
-                TASKS.lock().insert(position, old);
+        }
+        match HEAP_ALLOCATOR.task_exited(task.pid) {
+            Some(ref heap) if heap.used > 0 => trace_warn!(
+                "task {} leaked {} bytes of heap ({} allocations, {} peak)",
+                task.pid,
+                heap.used,
+                heap.allocations,
+                heap.peak
+            ),
+            _ => (),
+        }
+        if let Some(stack) = task.stack {
+            FINISHED_STACKS.lock().push(stack);
+        }
+        if let Some(stack) = task.kernel_stack {
+            FINISHED_STACKS.lock().push(stack);
+        }
+        if let Some(space) = task.address_space {
+            FINISHED_ADDRESS_SPACES.lock().push(space);
+        }
+    }
+
+    // the traces are written after the task lists are unlocked, to keep the critical section short
+    for miss in misses.drain(..) {
+        trace_warn!(
+            "task {} ({}) missed its deadline {} at {}",
+            miss.pid,
+            miss.name,
+            miss.deadline,
+            miss.detected
+        );
+    }
+    if let Some(pid) = scheduled {
+        trace_debug!("scheduled task {}", pid);
+    }
+    memory::set_heap_owner(running_pid);
+    memory::set_heap_forbidden(heap_forbidden);
+    // a user task enters the kernel on its own kernel stack
+    if let Some(top) = kernel_stack {
+        interrupts::set_kernel_stack(top);
+    }
+    stack_pointer
+}
+
+/// Checks all jobs for missed deadlines and applies the `OverrunPolicy` of the tasks. Handler tasks
+/// are woken up.
+fn check_deadlines(
+    policy: &mut Scheduler,
+    tasks: &mut Vec<TaskData>,
+    running: &mut TaskData,
+    tsc: usize,
+    misses: &mut Vec<DeadlineMiss>,
+    handlers: &mut Vec<usize>,
+) {
+    for task in tasks.iter_mut().chain(Some(running).into_iter()) {
+        if let Some(miss) = deadline::check(task, tsc) {
+            if let OverrunPolicy::Handler(pid) = task.overrun_policy {
+                handlers.push(pid);
+            }
+            misses.push(miss);
+        }
+    }
+    for pid in handlers.drain(..) {
+        if let Some(handler) = tasks.iter_mut().find(|task| task.pid == pid) {
+            if handler.status == TaskStatus::SLEEPING {
+                handler.release(tsc);
+                policy.on_wake(handler, tsc);
             }
         }
+    }
+}
 
-        f.stack_pointer = to_run.stack_pointer;
-        f.instruction_pointer = to_run.instruction_pointer;
+/// Wakes up a sleeping task if its `sleep_ticks` are reached and releases a new job. If the task
+/// has to skip a job (see `OverrunPolicy::SkipNextJob`), it sleeps one more period.
+/// A blocked task whose `block_timeout` is reached is woken up and continues its job.
+fn wake_up(policy: &mut Scheduler, task: &mut TaskData, tsc: usize) {
+    if task.status == TaskStatus::BLOCKED && task.block_timeout < tsc {
+        task.status = TaskStatus::RUNNING;
+        task.block_timeout = usize::MAX;
+        task.timed_out = true;
+        policy.on_wake(task, tsc);
+        return;
+    }
+    if task.status != TaskStatus::SLEEPING || task.sleep_ticks >= tsc {
+        return;
+    }
+    if task.skip_next_job && task.period != 0 {
+        task.skip_next_job = false;
+        task.sleep_ticks += task.period;
+        return;
+    }
+    let release = task.sleep_ticks;
+    task.release(release);
+    policy.on_wake(task, tsc);
+}
 
-        RUNNING_TASK.lock().name = to_run.name;
-        RUNNING_TASK.lock().status = to_run.status;
-        RUNNING_TASK.lock().stack_pointer = to_run.stack_pointer;
-        RUNNING_TASK.lock().instruction_pointer = to_run.instruction_pointer;
-        RUNNING_TASK.lock().pid = to_run.pid;
-        RUNNING_TASK.lock().sleep_ticks = to_run.sleep_ticks;
-        RUNNING_TASK.lock().time_sleep = if (tsc as usize) < to_run.last_time_stamp {
+/// Removes all finished tasks from `tasks`, e.g. tasks which were killed. The running task is
+/// removed by `switch_task()`.
+fn remove_finished(
+    policy: &mut Scheduler,
+    tasks: &mut Vec<TaskData>,
+    finished: &mut Vec<FinishedTask>,
+) {
+    for task in tasks.iter_mut() {
+        if task.status == TaskStatus::FINISHED {
+            exit_task(policy, task, finished);
+        }
+    }
+    tasks.retain(|task| task.status != TaskStatus::FINISHED);
+}
+
+/// Removes a finished task from the system. Its utilization is released and the policy is
+/// informed. The stack resources which the killed task still held are released, so the system
+/// ceiling drops again. The task is added to `finished`, so its kernel mutexes are unlocked, its
+/// handle is informed and its stack and address space are freed.
+fn exit_task(policy: &mut Scheduler, task: &mut TaskData, finished: &mut Vec<FinishedTask>) {
+    if task.period != 0 {
+        admission::release(task.wcet, task.period, task.deadline);
+    }
+    policy.on_exit(task);
+    let held_resources = srp::release_all(task.pid);
+    let held_mutexes = task.boosts.clone();
+    task.boosts.clear();
+    task.update_priority();
+    finished.push(FinishedTask {
+        pid: task.pid,
+        exit_code: task.exit_code,
+        stack: task.stack.clone(),
+        address_space: task.address_space,
+        kernel_stack: task.kernel_stack.clone(),
+        held_mutexes,
+        held_resources,
+    });
+}
+
+/// Chooses the next task with the scheduling policy and switches to it, if it is not the running
+/// task. The old task is saved in `tasks`, unless it is finished. `stack_pointer` is replaced
+/// with the stack pointer of the new task. A finished task is added to `finished`.
+///
+/// # Return
+/// * `Option<usize>` - The pid of the new task or `None` if the running task keeps running.
+fn switch_task(
+    policy: &mut Scheduler,
+    stack_pointer: &mut VirtualAddress,
+    tsc: usize,
+    tasks: &mut Vec<TaskData>,
+    running: &mut TaskData,
+    finished: &mut Vec<FinishedTask>,
+) -> Option<usize> {
+    if !running.is_ready()
+        && running.status != TaskStatus::IDLE
+        && running.status != TaskStatus::FINISHED
+    {
+        policy.on_block(running);
+    }
+
+    let mut to_run = match policy.pick_next(tasks, running) {
+        Some(index) => tasks.remove(index),
+        None if running.is_ready() || running.status == TaskStatus::IDLE => return None,
+        None => {
+            let index = tasks
+                .iter()
+                .position(|task| task.status == TaskStatus::IDLE)
+                .expect("no idle task");
+            tasks.remove(index)
+        }
+    };
+
+    if running.status != TaskStatus::FINISHED {
+        let context = unsafe { context::get(*stack_pointer) };
+        let mut old = running.clone();
+        old.cpu_flags = context.rflags;
+        old.stack_pointer = *stack_pointer;
+        old.instruction_pointer = VirtualAddress(context.rip as usize);
+        old.time_active = if tsc < old.last_time_stamp {
             0
         } else {
-            tsc as usize - to_run.last_time_stamp
+            tsc - old.last_time_stamp
         };
-        RUNNING_TASK.lock().time_active = to_run.time_active;
-        RUNNING_TASK.lock().last_time_stamp = tsc as usize;
+        old.last_time_stamp = tsc;
+        // does not allocate, a task was removed from `tasks` above
+        tasks.push(old);
+    } else {
+        exit_task(policy, running, finished);
+    }
+
+    if to_run.status == TaskStatus::READY {
+        to_run.release(tsc);
+        policy.on_wake(&mut to_run, tsc);
     }
+    to_run.time_sleep = if tsc < to_run.last_time_stamp {
+        0
+    } else {
+        tsc - to_run.last_time_stamp
+    };
+    to_run.last_time_stamp = tsc;
+
+    *stack_pointer = to_run.stack_pointer;
+    let pid = to_run.pid;
+    *running = to_run;
+    Some(pid)
 }
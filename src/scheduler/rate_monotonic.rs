@@ -0,0 +1,47 @@
+//! Fixed priority Rate Monotonic scheduling.
+//! The priority of a task is given by its period, the shorter the period the higher the priority.
+//! Aperiodic tasks have the lowest priority and are scheduled round robin.
+use core::usize;
+use scheduler::admission::UtilizationBound;
+use scheduler::{min_ready_by_key, Scheduler};
+use tasks::TaskData;
+
+/// Rate Monotonic policy. It has no state, the priority is derived from the period.
+pub struct RateMonotonic;
+
+/// Returns the rate monotonic priority of a task. A smaller value means a higher priority.
+fn rate(task: &TaskData) -> usize {
+    if task.period == 0 {
+        usize::MAX
+    } else {
+        task.period
+    }
+}
+
+impl Scheduler for RateMonotonic {
+    fn name(&self) -> &'static str {
+        "RM"
+    }
+
+    fn bound(&self) -> UtilizationBound {
+        UtilizationBound::RateMonotonic
+    }
+
+    /// The running task is only preempted by a task with a shorter or equal period.
+    fn pick_next(&mut self, tasks: &[TaskData], running: &TaskData) -> Option<usize> {
+        match min_ready_by_key(tasks, rate) {
+            Some(index) if !running.is_ready() || rate(&tasks[index]) <= rate(running) => {
+                Some(index)
+            }
+            _ => None,
+        }
+    }
+
+    fn on_tick(&mut self, _running: &mut TaskData, _tsc: usize) {}
+
+    fn on_block(&mut self, _task: &TaskData) {}
+
+    fn on_wake(&mut self, _task: &mut TaskData, _tsc: usize) {}
+
+    fn on_exit(&mut self, _task: &TaskData) {}
+}
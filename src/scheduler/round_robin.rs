@@ -0,0 +1,57 @@
+//! Round robin scheduling.
+//! Every ready task runs for `QUANTUM` calls of the scheduler, then the task which waited the
+//! longest is scheduled. Deadlines and periods are ignored.
+use scheduler::admission::UtilizationBound;
+use scheduler::{min_ready_by_key, Scheduler};
+use tasks::TaskData;
+
+/// Number of scheduler calls a task may run before it is preempted (about 25ms).
+const QUANTUM: usize = 3;
+
+/// Round robin policy.
+pub struct RoundRobin {
+    /// Number of scheduler calls since the running task got the cpu.
+    elapsed: usize,
+}
+
+impl RoundRobin {
+    pub fn new() -> Self {
+        RoundRobin { elapsed: 0 }
+    }
+}
+
+impl Scheduler for RoundRobin {
+    fn name(&self) -> &'static str {
+        "RR"
+    }
+
+    /// Round robin gives no guarantees, the EDF bound only prevents an overload.
+    fn bound(&self) -> UtilizationBound {
+        UtilizationBound::Edf
+    }
+
+    fn pick_next(&mut self, tasks: &[TaskData], running: &TaskData) -> Option<usize> {
+        if running.is_ready() && self.elapsed < QUANTUM {
+            return None;
+        }
+        let next = min_ready_by_key(tasks, |_| 0);
+        if next.is_some() {
+            self.elapsed = 0;
+        }
+        next
+    }
+
+    fn on_tick(&mut self, _running: &mut TaskData, _tsc: usize) {
+        self.elapsed += 1;
+    }
+
+    fn on_block(&mut self, _task: &TaskData) {
+        self.elapsed = QUANTUM;
+    }
+
+    fn on_wake(&mut self, _task: &mut TaskData, _tsc: usize) {}
+
+    fn on_exit(&mut self, _task: &TaskData) {
+        self.elapsed = QUANTUM;
+    }
+}
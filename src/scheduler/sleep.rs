@@ -0,0 +1,38 @@
+//! The first scheduling policy of the system, based on the sleep time of the tasks.
+//! A task which has never run before is scheduled first. Otherwise the task whose sleep ended
+//! first is scheduled. The running task is always preempted if another task is ready.
+use scheduler::admission::UtilizationBound;
+use scheduler::{min_ready_by_key, Scheduler};
+use tasks::{TaskData, TaskStatus};
+
+/// Sleep based policy. It has no state, the `sleep_ticks` of the tasks are used.
+pub struct Sleep;
+
+impl Scheduler for Sleep {
+    fn name(&self) -> &'static str {
+        "SLEEP"
+    }
+
+    /// The policy gives no guarantees, the EDF bound only prevents an overload.
+    fn bound(&self) -> UtilizationBound {
+        UtilizationBound::Edf
+    }
+
+    fn pick_next(&mut self, tasks: &[TaskData], _running: &TaskData) -> Option<usize> {
+        min_ready_by_key(tasks, |task| {
+            if task.status == TaskStatus::READY {
+                0
+            } else {
+                task.sleep_ticks
+            }
+        })
+    }
+
+    fn on_tick(&mut self, _running: &mut TaskData, _tsc: usize) {}
+
+    fn on_block(&mut self, _task: &TaskData) {}
+
+    fn on_wake(&mut self, _task: &mut TaskData, _tsc: usize) {}
+
+    fn on_exit(&mut self, _task: &TaskData) {}
+}
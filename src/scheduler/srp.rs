@@ -0,0 +1,149 @@
+//! Stack Resource Policy (SRP).
+//! Every task has a preemption level (see `TaskData::preemption_level`), which has to be higher
+//! for tasks with shorter relative deadlines, e.g. the deadline monotonic priority. Every resource
+//! (a kernel `Mutex` with `Protocol::StackResource`) has a ceiling, the highest preemption level of
+//! all tasks which use it. The system ceiling is the highest ceiling of all locked resources.
+//!
+//! A task may only preempt the running task if its preemption level is higher than the system
+//! ceiling. Tasks which hold a locked resource are always allowed to run, so they can release it.
+//! With this rule a task never blocks on a resource once it runs, so the shared resources can
+//! neither deadlock nor cause unbounded priority inversion with EDF.
+//!
+//! The check is done by `min_ready_by_key()`, so it is used by all policies which choose their
+//! task with it. The fixed priority policy uses the ceiling as priority boost instead.
+use spin::Mutex;
+use tasks::TaskData;
+
+/// Maximum number of resources which can be locked at the same time.
+const MAX_LOCKED_RESOURCES: usize = 16;
+
+/// A locked resource.
+#[derive(Debug, Clone, Copy)]
+struct LockedResource {
+    /// Address of the resource.
+    resource: usize,
+    /// Pid of the task which holds the resource.
+    owner: usize,
+    /// Ceiling of the resource.
+    ceiling: u8,
+}
+
+/// Stack of the locked resources. Resources are normally unlocked in the reversed order.
+#[derive(Debug, Clone, Copy)]
+pub struct SystemCeiling {
+    resources: [LockedResource; MAX_LOCKED_RESOURCES],
+    len: usize,
+}
+
+impl SystemCeiling {
+    /// Returns the system ceiling, `None` if no resource is locked.
+    pub fn level(&self) -> Option<u8> {
+        self.resources[..self.len]
+            .iter()
+            .map(|locked| locked.ceiling)
+            .max()
+    }
+
+    /// Returns `true` if the task may run with this system ceiling.
+    ///
+    /// # Arguments
+    /// * `task` - (&TaskData) The task which should run.
+    pub fn allows(&self, task: &TaskData) -> bool {
+        match self.level() {
+            None => true,
+            Some(level) => {
+                task.preemption_level > level
+                    || self.resources[..self.len]
+                        .iter()
+                        .any(|locked| locked.owner == task.pid)
+            }
+        }
+    }
+}
+
+/// The locked resources of the system. Only locked with disabled interrupts.
+static SYSTEM_CEILING: Mutex<SystemCeiling> = Mutex::new(SystemCeiling {
+    resources: [LockedResource {
+        resource: 0,
+        owner: 0,
+        ceiling: 0,
+    }; MAX_LOCKED_RESOURCES],
+    len: 0,
+});
+
+/// Adds a locked resource, which may raise the system ceiling. Must be called with disabled
+/// interrupts.
+///
+/// # Arguments
+/// * `resource` - (usize) Address of the resource.
+/// * `owner` - (usize) Pid of the task which locked the resource.
+/// * `ceiling` - (u8) Ceiling of the resource.
+pub fn lock(resource: usize, owner: usize, ceiling: u8) {
+    let mut guard = SYSTEM_CEILING.lock();
+    let system = &mut *guard;
+    assert!(
+        system.len < MAX_LOCKED_RESOURCES,
+        "too many locked stack resources"
+    );
+    system.resources[system.len] = LockedResource {
+        resource,
+        owner,
+        ceiling,
+    };
+    system.len += 1;
+}
+
+/// Removes a resource which was unlocked, which may lower the system ceiling. Must be called with
+/// disabled interrupts.
+///
+/// # Arguments
+/// * `resource` - (usize) Address of the resource.
+///
+/// # Return
+/// * `bool` - `true` if the system ceiling was lowered.
+pub fn unlock(resource: usize) -> bool {
+    let mut guard = SYSTEM_CEILING.lock();
+    let system = &mut *guard;
+    let before = system.level();
+    let len = system.len;
+    let position = system.resources[..len]
+        .iter()
+        .rposition(|locked| locked.resource == resource);
+    if let Some(index) = position {
+        for i in index..len - 1 {
+            system.resources[i] = system.resources[i + 1];
+        }
+        system.len -= 1;
+    }
+    system.level() < before
+}
+
+/// Removes all resources which are held by a task, e.g. because it was killed. Otherwise the
+/// system ceiling would stay raised and block all tasks at or below it forever. Must be called
+/// with disabled interrupts.
+///
+/// # Arguments
+/// * `owner` - (usize) Pid of the task.
+///
+/// # Return
+/// * `usize` - Number of removed resources.
+pub fn release_all(owner: usize) -> usize {
+    let mut guard = SYSTEM_CEILING.lock();
+    let system = &mut *guard;
+    let mut kept = 0;
+    for i in 0..system.len {
+        if system.resources[i].owner != owner {
+            system.resources[kept] = system.resources[i];
+            kept += 1;
+        }
+    }
+    let removed = system.len - kept;
+    system.len = kept;
+    removed
+}
+
+/// Returns a copy of the locked resources. Used by the scheduler, must be called with disabled
+/// interrupts.
+pub fn system_ceiling() -> SystemCeiling {
+    *SYSTEM_CEILING.lock()
+}
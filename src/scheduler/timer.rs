@@ -0,0 +1,171 @@
+//! Software timers.
+//! A timer calls a function at an absolute timestamp. One-shot timers are stopped afterwards,
+//! auto-reload timers are started again with their period. The next expiry of an auto-reload
+//! timer is computed from the last expiry, so the timer does not drift.
+//!
+//! The timers are checked by the scheduler on every timer interrupt (about every 8.38ms), so a
+//! callback runs at the first interrupt after its expiry. Callbacks run inside the interrupt with
+//! disabled interrupts. They must be short and must not block, print or call the scheduler. To wake
+//! up a task, a callback can use `sync::EventGroup::set_from_isr()`.
+use core::usize;
+use features::{ms_to_ticks, without_interrupts};
+use spin::Mutex;
+use x86_64::instructions::rdtsc;
+
+/// Maximum number of timers.
+const MAX_TIMERS: usize = 32;
+
+/// Function which is called when a timer expires. The argument is given when the timer is created.
+pub type TimerCallback = fn(usize);
+
+/// Defines what happens after a timer expired.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimerMode {
+    /// The timer is stopped.
+    OneShot,
+    /// The timer is started again with its period.
+    AutoReload,
+}
+
+/// Identifies a timer, returned by `create()`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimerHandle(usize);
+
+/// A software timer.
+#[derive(Clone, Copy)]
+struct Timer {
+    callback: TimerCallback,
+    argument: usize,
+    mode: TimerMode,
+    /// Period in tsc ticks, used by `start()` and for auto-reload.
+    period: usize,
+    /// Timestamp of the next expiry.
+    expiry: usize,
+    /// Set if the timer is started.
+    active: bool,
+}
+
+/// All timers. Only locked with disabled interrupts.
+static TIMERS: Mutex<[Option<Timer>; MAX_TIMERS]> = Mutex::new([None; MAX_TIMERS]);
+
+/// Creates a new stopped timer.
+///
+/// # Arguments
+/// * `callback` - (TimerCallback) Function which is called when the timer expires.
+/// * `argument` - (usize) Argument for the callback.
+/// * `period` - (u64) Period in milliseconds, must not be `0`.
+/// * `mode` - (TimerMode) One-shot or auto-reload.
+///
+/// # Return
+/// * `Option<TimerHandle>` - The new timer, `None` if all `MAX_TIMERS` timers are used.
+pub fn create(
+    callback: TimerCallback,
+    argument: usize,
+    period: u64,
+    mode: TimerMode,
+) -> Option<TimerHandle> {
+    let period = ms_to_ticks(period);
+    assert!(period > 0, "timer without period");
+    without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        let index = timers.iter().position(|timer| timer.is_none())?;
+        timers[index] = Some(Timer {
+            callback,
+            argument,
+            mode,
+            period,
+            expiry: 0,
+            active: false,
+        });
+        Some(TimerHandle(index))
+    })
+}
+
+/// Deletes a timer. The handle must not be used anymore.
+pub fn delete(handle: TimerHandle) {
+    without_interrupts(|| TIMERS.lock()[handle.0] = None);
+}
+
+/// Runs `f` with a timer. Panics if the timer was deleted.
+fn with_timer<F>(handle: TimerHandle, f: F)
+where
+    F: FnOnce(&mut Timer),
+{
+    without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        f(timers[handle.0].as_mut().expect("timer was deleted"));
+    });
+}
+
+/// Starts a timer, it expires one period after now. A started timer is restarted.
+pub fn start(handle: TimerHandle) {
+    let now = rdtsc() as usize;
+    with_timer(handle, |timer| {
+        timer.expiry = now.saturating_add(timer.period);
+        timer.active = true;
+    });
+}
+
+/// Stops a timer. The callback is not called until the timer is started again.
+pub fn stop(handle: TimerHandle) {
+    with_timer(handle, |timer| timer.active = false);
+}
+
+/// Restarts a timer, it expires one period after now. Same as `start()`.
+pub fn reset(handle: TimerHandle) {
+    start(handle);
+}
+
+/// Changes the period of a timer. It is used from the next start or reload on.
+///
+/// # Arguments
+/// * `handle` - (TimerHandle) The timer.
+/// * `period` - (u64) The new period in milliseconds, must not be `0`.
+pub fn change_period(handle: TimerHandle, period: u64) {
+    let period = ms_to_ticks(period);
+    assert!(period > 0, "timer without period");
+    with_timer(handle, |timer| timer.period = period);
+}
+
+/// Returns `true` if the timer is started.
+pub fn is_active(handle: TimerHandle) -> bool {
+    without_interrupts(|| {
+        TIMERS.lock()[handle.0]
+            .map(|timer| timer.active)
+            .unwrap_or(false)
+    })
+}
+
+/// Calls the callbacks of all expired timers. Called by the scheduler with disabled interrupts.
+/// The callbacks are called after `TIMERS` is unlocked, so they can start or stop timers.
+///
+/// # Arguments
+/// * `tsc` - (usize) The current timestamp.
+pub fn expire(tsc: usize) {
+    let mut expired: [Option<(TimerCallback, usize)>; MAX_TIMERS] = [None; MAX_TIMERS];
+    {
+        let mut timers = TIMERS.lock();
+        for (i, slot) in timers.iter_mut().enumerate() {
+            if let Some(ref mut timer) = *slot {
+                if !timer.active || timer.expiry > tsc {
+                    continue;
+                }
+                expired[i] = Some((timer.callback, timer.argument));
+                match timer.mode {
+                    TimerMode::OneShot => timer.active = false,
+                    TimerMode::AutoReload => {
+                        // expiries which were missed are skipped
+                        while timer.expiry <= tsc {
+                            timer.expiry = timer.expiry.saturating_add(timer.period);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    for entry in expired.iter() {
+        if let Some((callback, argument)) = *entry {
+            callback(argument);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! Event groups. A task can wait until any or all bits of a mask are set.
+use alloc::Vec;
+use core::usize;
+use features::{ms_to_ticks, without_interrupts};
+use scheduler;
+use spin;
+use sync::WAIT_QUEUE_CAPACITY;
+use tasks;
+use x86_64::instructions::rdtsc;
+
+/// Defines when a waiting task is woken up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WaitMode {
+    /// At least one bit of the mask is set.
+    Any,
+    /// All bits of the mask are set.
+    All,
+}
+
+/// A task which waits for bits.
+struct Waiter {
+    pid: usize,
+    mask: usize,
+    mode: WaitMode,
+}
+
+/// Bits and waiting tasks of an event group.
+struct EventState {
+    bits: usize,
+    waiters: Vec<Waiter>,
+}
+
+/// Event group with 64 event bits. Tasks can wait for bits with a timeout, tasks and
+/// interrupt handlers can set them.
+///
+/// A woken task checks the bits again, so another task can clear them first.
+pub struct EventGroup {
+    /// Only locked with disabled interrupts.
+    state: spin::Mutex<EventState>,
+}
+
+/// Checks if the bits fulfill the condition of a waiting task.
+fn fulfilled(bits: usize, mask: usize, mode: WaitMode) -> bool {
+    match mode {
+        WaitMode::Any => bits & mask != 0,
+        WaitMode::All => bits & mask == mask,
+    }
+}
+
+impl EventGroup {
+    /// Creates a new event group with all bits cleared.
+    pub fn new() -> Self {
+        EventGroup {
+            state: spin::Mutex::new(EventState {
+                bits: 0,
+                waiters: Vec::with_capacity(WAIT_QUEUE_CAPACITY),
+            }),
+        }
+    }
+
+    /// Sets bits and wakes up all tasks whose condition is fulfilled. The scheduler is called if a
+    /// task was woken up.
+    ///
+    /// # Arguments
+    /// * `bits` - (usize) The bits to set.
+    pub fn set(&self, bits: usize) {
+        if self.set_from_isr(bits) {
+            scheduler::reschedule();
+        }
+    }
+
+    /// Sets bits like `set()`, but does not call the scheduler. The woken tasks run after the next
+    /// timer interrupt. Can be used by interrupt handlers, but not while the scheduler holds the
+    /// task lists (see `scheduler::schedule()`).
+    ///
+    /// # Arguments
+    /// * `bits` - (usize) The bits to set.
+    ///
+    /// # Return
+    /// * `bool` - `true` if a task was woken up.
+    pub fn set_from_isr(&self, bits: usize) -> bool {
+        tasks::kernel_call(|| {
+            without_interrupts(|| {
+                let mut guard = self.state.lock();
+                let state = &mut *guard;
+                state.bits |= bits;
+                let mut woken = false;
+                let mut i = 0;
+                while i < state.waiters.len() {
+                    if fulfilled(state.bits, state.waiters[i].mask, state.waiters[i].mode) {
+                        let waiter = state.waiters.remove(i);
+                        woken |= scheduler::unblock(waiter.pid);
+                    } else {
+                        i += 1;
+                    }
+                }
+                woken
+            })
+        })
+    }
+
+    /// Clears bits.
+    ///
+    /// # Arguments
+    /// * `bits` - (usize) The bits to clear.
+    pub fn clear(&self, bits: usize) {
+        tasks::kernel_call(|| without_interrupts(|| self.state.lock().bits &= !bits));
+    }
+
+    /// Waits until the bits of `mask` fulfill the condition.
+    ///
+    /// # Arguments
+    /// * `mask` - (usize) The bits to wait for, must not be `0`.
+    /// * `mode` - (WaitMode) Wait for any or all bits.
+    /// * `clear` - (bool) Clears the bits of `mask` when the condition is fulfilled.
+    ///
+    /// # Return
+    /// * `usize` - The bits at the time the condition was fulfilled, before they are cleared.
+    pub fn wait(&self, mask: usize, mode: WaitMode, clear: bool) -> usize {
+        self.wait_until(mask, mode, clear, usize::MAX)
+            .expect("wait without timeout failed")
+    }
+
+    /// Waits like `wait()`, but at most until the timeout is reached.
+    ///
+    /// # Arguments
+    /// * `mask` - (usize) The bits to wait for, must not be `0`.
+    /// * `mode` - (WaitMode) Wait for any or all bits.
+    /// * `clear` - (bool) Clears the bits of `mask` when the condition is fulfilled.
+    /// * `timeout` - (u64) Timeout in milliseconds.
+    ///
+    /// # Return
+    /// * `Option<usize>` - The bits at the time the condition was fulfilled, `None` if the timeout
+    /// was reached.
+    pub fn wait_timeout(
+        &self,
+        mask: usize,
+        mode: WaitMode,
+        clear: bool,
+        timeout: u64,
+    ) -> Option<usize> {
+        self.wait_until(
+            mask,
+            mode,
+            clear,
+            (rdtsc() as usize).saturating_add(ms_to_ticks(timeout)),
+        )
+    }
+
+    /// Waits for the bits, blocks the running task at most until the timestamp `deadline`.
+    fn wait_until(
+        &self,
+        mask: usize,
+        mode: WaitMode,
+        clear: bool,
+        deadline: usize,
+    ) -> Option<usize> {
+        tasks::kernel_call(|| {
+            assert!(mask != 0, "wait for an empty event mask");
+            let pid = scheduler::current_pid();
+            loop {
+                let (bits, expired) = without_interrupts(|| {
+                    let mut state = self.state.lock();
+                    let bits = state.bits;
+                    if fulfilled(bits, mask, mode) {
+                        if clear {
+                            state.bits &= !mask;
+                        }
+                        return (Some(bits), false);
+                    }
+                    if rdtsc() as usize >= deadline {
+                        return (None, true);
+                    }
+                    state.waiters.push(Waiter { pid, mask, mode });
+                    drop(state);
+                    if !scheduler::block_current_until(deadline) {
+                        self.state.lock().waiters.retain(|waiter| waiter.pid != pid);
+                    }
+                    (None, false)
+                });
+                if bits.is_some() || expired {
+                    return bits;
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,101 @@
+//! Blocking synchronization primitives for tasks.
+//! In contrast to `spin::Mutex`, a task which has to wait does not spin or poll with `msleep`. It
+//! is added to the wait queue of the object, gets the status `BLOCKED` and is not scheduled until
+//! it is woken up by another task. Supported objects are:
+//!
+//!     1. Semaphore  -> Counting semaphore
+//!     2. Mutex      -> Mutual exclusion with an owner, protects its data like `spin::Mutex`.
+//!                      Supports priority inheritance, priority ceilings and the Stack Resource
+//!                      Policy (see `Protocol`).
+//!     3. Queue      -> Bounded message queue with blocking send and receive and timeouts.
+//!     4. EventGroup -> Event bits, tasks wait until any or all bits of a mask are set. The bits
+//!                      can also be set by interrupt handlers.
+//!
+//! Waiting tasks are woken up in priority order, tasks with the same priority in FIFO order. The
+//! objects may only be used by tasks and only after the scheduler is running. Interrupt handlers
+//! may only use `EventGroup::set_from_isr()`.
+//!
+//! The state of the objects is on the heap, so their functions switch to the kernel address space
+//! when they are called by an isolated task (see `tasks::kernel_call()`). The data of a `Mutex` is
+//! accessed in the address space of the task, so it must not be on the heap then.
+use alloc::VecDeque;
+use scheduler;
+
+mod event;
+mod mutex;
+mod protocol;
+mod queue;
+mod semaphore;
+
+pub use self::event::{EventGroup, WaitMode};
+pub use self::mutex::{Mutex, MutexGuard};
+pub use self::protocol::{PriorityBoosts, Protocol};
+pub use self::queue::Queue;
+pub use self::semaphore::Semaphore;
+
+/// Number of waiting tasks an object can hold without allocating. Blocking does not allocate up
+/// to this number, so tasks which forbid the heap (see `tasks::forbid_heap()`) can use the objects.
+const WAIT_QUEUE_CAPACITY: usize = 8;
+
+/// Queue of the pids of the tasks which are blocked on an object.
+pub struct WaitQueue {
+    pids: VecDeque<usize>,
+}
+
+impl WaitQueue {
+    pub fn new() -> Self {
+        WaitQueue {
+            pids: VecDeque::with_capacity(WAIT_QUEUE_CAPACITY),
+        }
+    }
+
+    /// Adds a task to the end of the queue. The task has to be blocked afterwards with
+    /// `scheduler::block_current()`, without enabling the interrupts in between. Allocates if more
+    /// than `WAIT_QUEUE_CAPACITY` tasks are waiting.
+    ///
+    /// # Arguments
+    /// * `pid` - (usize) Pid of the running task.
+    pub fn push(&mut self, pid: usize) {
+        self.pids.push_back(pid);
+    }
+
+    /// Wakes up the waiting task with the highest priority. Tasks which are not blocked anymore,
+    /// e.g. because they were killed, are skipped.
+    ///
+    /// # Return
+    /// * `Option<usize>` - Pid of the woken task, `None` if no task was waiting.
+    pub fn wake_one(&mut self) -> Option<usize> {
+        loop {
+            let mut best: Option<(usize, u8)> = None;
+            for (i, pid) in self.pids.iter().enumerate() {
+                let priority = scheduler::with_task(*pid, |task| task.priority).unwrap_or(0);
+                match best {
+                    Some((_, p)) if p >= priority => {}
+                    _ => best = Some((i, priority)),
+                }
+            }
+            let (index, _) = best?;
+            let pid = self.pids.remove(index).expect("wait queue index invalid");
+            if scheduler::unblock(pid) {
+                return Some(pid);
+            }
+        }
+    }
+
+    /// Removes a task from the queue, e.g. because its timeout was reached.
+    ///
+    /// # Arguments
+    /// * `pid` - (usize) Pid of the task.
+    pub fn remove(&mut self, pid: usize) {
+        self.pids.retain(|p| *p != pid);
+    }
+
+    /// Returns the highest priority of all waiting tasks, `0` if no task is waiting.
+    pub fn highest_priority(&self) -> u8 {
+        self.pids
+            .iter()
+            .filter_map(|pid| scheduler::with_task(*pid, |task| task.priority))
+            .max()
+            .unwrap_or(0)
+    }
+}
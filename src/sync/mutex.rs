@@ -0,0 +1,263 @@
+//! Kernel mutex with an owner.
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use features::without_interrupts;
+use scheduler;
+use scheduler::srp;
+use spin;
+use sync::{Protocol, WaitQueue};
+use tasks;
+use tasks::PRIORITY_LEVELS;
+
+/// Owner and wait queue of a mutex.
+struct MutexState {
+    /// Pid of the task which holds the mutex.
+    owner: Option<usize>,
+    /// Tasks which wait for the mutex.
+    waiters: WaitQueue,
+}
+
+/// The part of a `Mutex` which does not depend on the data. The held mutexes of a task are
+/// recorded with the address of their `RawMutex` (see `PriorityBoosts`), so the scheduler can
+/// unlock them when the task dies.
+struct RawMutex {
+    /// Only locked with disabled interrupts.
+    state: spin::Mutex<MutexState>,
+    protocol: Protocol,
+}
+
+/// Kernel mutex. A task which calls `lock()` while the mutex is held by another task is blocked.
+/// On `unlock` the mutex is passed directly to the waiting task with the highest priority. The
+/// `Protocol` defines how the priority of the owner is boosted. If the owner dies, e.g. because it
+/// is killed, the mutex is passed on like on `unlock` (see `unlock_dead_owner()`).
+///
+/// The mutex is not recursive, locking it twice from the same task panics.
+pub struct Mutex<T> {
+    raw: RawMutex,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+/// Gives access to the data of a locked `Mutex`. The mutex is unlocked when the guard is dropped.
+pub struct MutexGuard<'a, T: 'a> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    /// Creates a new unlocked mutex without a protocol.
+    ///
+    /// # Arguments
+    /// * `data` - (T) The data which is protected by the mutex.
+    pub fn new(data: T) -> Self {
+        Mutex::with_protocol(data, Protocol::None)
+    }
+
+    /// Creates a new unlocked mutex with a protocol against priority inversion.
+    ///
+    /// # Arguments
+    /// * `data` - (T) The data which is protected by the mutex.
+    /// * `protocol` - (Protocol) Defines how the priority of the owner is boosted. A ceiling must
+    /// be less than `PRIORITY_LEVELS`.
+    pub fn with_protocol(data: T, protocol: Protocol) -> Self {
+        match protocol {
+            Protocol::Ceiling(ceiling) | Protocol::StackResource(ceiling) => assert!(
+                (ceiling as usize) < PRIORITY_LEVELS,
+                "mutex ceiling {} is not a valid priority",
+                ceiling
+            ),
+            Protocol::None | Protocol::Inheritance => (),
+        }
+        Mutex {
+            raw: RawMutex {
+                state: spin::Mutex::new(MutexState {
+                    owner: None,
+                    waiters: WaitQueue::new(),
+                }),
+                protocol,
+            },
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Locks the mutex. If the mutex is held by another task, the running task is blocked until the
+    /// mutex is passed to it.
+    ///
+    /// # Return
+    /// * `MutexGuard<T>` - Guard which unlocks the mutex when it is dropped.
+    pub fn lock(&self) -> MutexGuard<T> {
+        self.raw.lock();
+        MutexGuard { mutex: self }
+    }
+
+    /// Locks the mutex without blocking.
+    ///
+    /// # Return
+    /// * `Option<MutexGuard<T>>` - `None` if the mutex is held by a task.
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        if self.raw.try_lock() {
+            Some(MutexGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+}
+
+impl RawMutex {
+    /// Identifies the mutex in the `PriorityBoosts` of the owner and in the system ceiling.
+    fn id(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Records the mutex in the `PriorityBoosts` of the new owner and boosts its priority,
+    /// depending on the protocol. A mutex without a protocol is recorded with the priority `0`. A
+    /// stack resource also raises the system ceiling. Must be called with disabled interrupts.
+    fn acquired(&self, pid: usize, waiters: &WaitQueue) {
+        let id = self.id();
+        let boost = match self.protocol {
+            Protocol::None => 0,
+            Protocol::Inheritance => waiters.highest_priority(),
+            Protocol::Ceiling(ceiling) => ceiling,
+            Protocol::StackResource(ceiling) => {
+                srp::lock(id, pid, ceiling);
+                ceiling
+            }
+        };
+        scheduler::with_task(pid, |task| {
+            task.boosts.add(id, boost);
+            task.update_priority();
+        });
+    }
+
+    /// Locks the mutex, blocks the running task until the mutex is passed to it if it is held.
+    fn lock(&self) {
+        tasks::kernel_call(|| {
+            let pid = scheduler::current_pid();
+            without_interrupts(|| {
+                let mut state = self.state.lock();
+                let owner = state.owner;
+                match owner {
+                    None => {
+                        state.owner = Some(pid);
+                        self.acquired(pid, &state.waiters);
+                    }
+                    Some(owner) => {
+                        assert!(owner != pid, "kernel mutex locked twice by the same task");
+                        state.waiters.push(pid);
+                        if self.protocol == Protocol::Inheritance {
+                            let id = self.id();
+                            let priority =
+                                scheduler::with_task(pid, |task| task.priority).unwrap_or(0);
+                            scheduler::with_task(owner, |task| {
+                                task.boosts.raise(id, priority);
+                                task.update_priority();
+                            });
+                        }
+                        drop(state);
+                        // the mutex is passed to this task by `unlock()`
+                        scheduler::block_current();
+                    }
+                }
+            });
+        })
+    }
+
+    /// Locks the mutex without blocking.
+    ///
+    /// # Return
+    /// * `bool` - `false` if the mutex is held by a task.
+    fn try_lock(&self) -> bool {
+        tasks::kernel_call(|| {
+            let pid = scheduler::current_pid();
+            without_interrupts(|| {
+                let mut state = self.state.lock();
+                if state.owner.is_none() {
+                    state.owner = Some(pid);
+                    self.acquired(pid, &state.waiters);
+                    true
+                } else {
+                    false
+                }
+            })
+        })
+    }
+
+    /// Passes the mutex to the waiting task with the highest priority, or unlocks it if no task is
+    /// waiting. The boost of the mutex is removed from the old owner and a stack resource lowers
+    /// the system ceiling. The scheduler is only called if a task was woken up, the priority of the
+    /// old owner was lowered or the system ceiling was lowered, otherwise no other task can be
+    /// allowed to run now.
+    fn unlock(&self) {
+        tasks::kernel_call(|| {
+            if without_interrupts(|| self.pass_on()) {
+                scheduler::reschedule();
+            }
+        })
+    }
+
+    /// Removes the mutex from the old owner and passes it to the waiting task with the highest
+    /// priority. Must be called with disabled interrupts.
+    ///
+    /// # Return
+    /// * `bool` - `true` if the scheduler has to be called, see `unlock()`.
+    fn pass_on(&self) -> bool {
+        let mut state = self.state.lock();
+        let mut lowered = false;
+        if let Some(owner) = state.owner {
+            let id = self.id();
+            lowered = scheduler::with_task(owner, |task| {
+                let old_priority = task.priority;
+                task.boosts.remove(id);
+                task.update_priority();
+                task.priority < old_priority
+            })
+            .unwrap_or(false);
+        }
+        if let Protocol::StackResource(_) = self.protocol {
+            lowered |= srp::unlock(self.id());
+        }
+        let next = state.waiters.wake_one();
+        state.owner = next;
+        if let Some(pid) = next {
+            self.acquired(pid, &state.waiters);
+        }
+        next.is_some() || lowered
+    }
+}
+
+/// Unlocks a mutex whose owner died while it held the mutex, e.g. because it was killed. The mutex
+/// is passed to the waiting task with the highest priority, like on `unlock`. The data may be
+/// inconsistent, because the owner did not finish its critical section. Called by the scheduler
+/// after the task was removed, with disabled interrupts.
+///
+/// # Arguments
+/// * `mutex` - (usize) Address of the `RawMutex`, as recorded in the `PriorityBoosts` of the task.
+/// * `owner` - (usize) Pid of the dead task.
+pub fn unlock_dead_owner(mutex: usize, owner: usize) {
+    // the guard of the dead task still borrows the mutex, so it was not moved or dropped
+    let mutex = unsafe { &*(mutex as *const RawMutex) };
+    if mutex.state.lock().owner == Some(owner) {
+        mutex.pass_on();
+    }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.raw.unlock();
+    }
+}
@@ -0,0 +1,184 @@
+//! Protocols against priority inversion for the kernel `Mutex`.
+//! While a task holds a mutex, its priority can be boosted (see `TaskData::priority`):
+//!
+//!     1. Inheritance   -> The owner inherits the priority of the highest priority task which
+//!                         waits for the mutex.
+//!     2. Ceiling       -> Immediate priority ceiling. The owner runs with the ceiling priority of
+//!                         the mutex as soon as it gets it. The ceiling must be at least the
+//!                         priority of every task which uses the mutex.
+//!     3. StackResource -> Stack Resource Policy for EDF (see `scheduler::srp`). The ceiling is
+//!                         the highest preemption level of all tasks which use the mutex. The
+//!                         owner is also boosted to the ceiling like with `Ceiling`, so the mutex
+//!                         is protected with the fixed priority policy too.
+//!
+//! The boosts of all held mutexes are stored in the `TaskData` of the owner, so nested mutexes
+//! release their boosts independently. Mutexes without a protocol are stored too, so the mutexes
+//! of a task which dies can be unlocked. Inheritance is not transitive: if the owner itself waits
+//! for another mutex, the owner of that mutex is not boosted.
+//!
+//! The priorities are used by the fixed priority policy of the scheduler.
+use sync::mutex;
+
+/// Maximum number of kernel mutexes a task can hold at the same time.
+const MAX_HELD_MUTEXES: usize = 8;
+
+/// Protocol of a kernel `Mutex`, selected when the mutex is created.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    /// The priority of the owner is not changed.
+    None,
+    /// Priority inheritance.
+    Inheritance,
+    /// Immediate priority ceiling with the given ceiling priority.
+    Ceiling(u8),
+    /// Stack Resource Policy with the given ceiling preemption level.
+    StackResource(u8),
+}
+
+/// Priority boosts of the mutexes which are held by a task. Mutexes without a protocol have the
+/// boost `0`.
+#[derive(Debug, Clone)]
+pub struct PriorityBoosts {
+    /// Pairs of the mutex (its address) and the priority the owner needs because of this mutex.
+    entries: [(usize, u8); MAX_HELD_MUTEXES],
+    /// Number of used entries.
+    len: usize,
+}
+
+impl PriorityBoosts {
+    pub const fn new() -> Self {
+        PriorityBoosts {
+            entries: [(0, 0); MAX_HELD_MUTEXES],
+            len: 0,
+        }
+    }
+
+    /// Adds the boost of a mutex which was locked by the task.
+    ///
+    /// # Arguments
+    /// * `mutex` - (usize) Address of the mutex.
+    /// * `priority` - (u8) Priority the owner needs because of the mutex.
+    pub fn add(&mut self, mutex: usize, priority: u8) {
+        assert!(
+            self.len < MAX_HELD_MUTEXES,
+            "too many nested kernel mutexes"
+        );
+        self.entries[self.len] = (mutex, priority);
+        self.len += 1;
+    }
+
+    /// Raises the boost of a held mutex, e.g. because a task with a higher priority waits for it.
+    ///
+    /// # Arguments
+    /// * `mutex` - (usize) Address of the mutex.
+    /// * `priority` - (u8) New priority, ignored if it is lower than the current boost.
+    pub fn raise(&mut self, mutex: usize, priority: u8) {
+        for entry in self.entries[..self.len].iter_mut() {
+            if entry.0 == mutex && entry.1 < priority {
+                entry.1 = priority;
+            }
+        }
+    }
+
+    /// Removes the boost of a mutex which was unlocked by the task.
+    ///
+    /// # Arguments
+    /// * `mutex` - (usize) Address of the mutex.
+    pub fn remove(&mut self, mutex: usize) {
+        let position = self.entries[..self.len]
+            .iter()
+            .position(|entry| entry.0 == mutex);
+        if let Some(index) = position {
+            for i in index..self.len - 1 {
+                self.entries[i] = self.entries[i + 1];
+            }
+            self.len -= 1;
+        }
+    }
+
+    /// Returns the number of held mutexes.
+    pub fn count(&self) -> usize {
+        self.len
+    }
+
+    /// Removes all boosts, e.g. because the task exits while it still holds mutexes.
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Unlocks all held mutexes of a task which died, so the tasks which wait for them are woken
+    /// up. Called by the scheduler with a copy of the boosts of the removed task.
+    ///
+    /// # Arguments
+    /// * `owner` - (usize) Pid of the dead task.
+    pub fn unlock_all(&self, owner: usize) {
+        for entry in self.entries[..self.len].iter() {
+            mutex::unlock_dead_owner(entry.0, owner);
+        }
+    }
+
+    /// Returns the highest boost, `0` if no boost is active.
+    pub fn max(&self) -> u8 {
+        self.entries[..self.len]
+            .iter()
+            .map(|entry| entry.1)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn the_highest_boost_is_used() {
+        let mut boosts = PriorityBoosts::new();
+        assert_eq!(boosts.max(), 0);
+        boosts.add(0x1000, 3);
+        boosts.add(0x2000, 0);
+        boosts.add(0x3000, 5);
+        assert_eq!(boosts.count(), 3);
+        assert_eq!(boosts.max(), 5);
+        boosts.remove(0x3000);
+        assert_eq!(boosts.max(), 3);
+        boosts.remove(0x1000);
+        assert_eq!(boosts.max(), 0);
+        assert_eq!(boosts.count(), 1);
+    }
+
+    #[test]
+    fn boosts_are_only_raised() {
+        let mut boosts = PriorityBoosts::new();
+        boosts.add(0x1000, 2);
+        boosts.add(0x2000, 4);
+        boosts.raise(0x1000, 6);
+        assert_eq!(boosts.max(), 6);
+        boosts.raise(0x1000, 1);
+        assert_eq!(boosts.max(), 6);
+        boosts.remove(0x1000);
+        assert_eq!(boosts.max(), 4);
+    }
+
+    #[test]
+    fn unknown_mutexes_are_ignored() {
+        let mut boosts = PriorityBoosts::new();
+        boosts.add(0x1000, 2);
+        boosts.raise(0x2000, 7);
+        boosts.remove(0x2000);
+        assert_eq!(boosts.count(), 1);
+        assert_eq!(boosts.max(), 2);
+        boosts.clear();
+        assert_eq!(boosts.count(), 0);
+        assert_eq!(boosts.max(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "too many nested kernel mutexes")]
+    fn too_many_mutexes_panic() {
+        let mut boosts = PriorityBoosts::new();
+        for mutex in 0..MAX_HELD_MUTEXES + 1 {
+            boosts.add(mutex, 1);
+        }
+    }
+}
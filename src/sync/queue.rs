@@ -0,0 +1,160 @@
+//! Bounded message queue (mailbox) for the communication between tasks.
+use alloc::VecDeque;
+use core::usize;
+use features::{ms_to_ticks, without_interrupts};
+use scheduler;
+use spin;
+use sync::WaitQueue;
+use tasks;
+use x86_64::instructions::rdtsc;
+
+/// Messages and wait queues of a message queue.
+struct QueueState<T> {
+    /// Messages in the order they were sent.
+    messages: VecDeque<T>,
+    /// Maximum number of messages.
+    capacity: usize,
+    /// Tasks which wait for a message.
+    receivers: WaitQueue,
+    /// Tasks which wait for a free slot.
+    senders: WaitQueue,
+}
+
+/// Bounded message queue. Messages are received in the order they were sent. A task which sends
+/// to a full queue or receives from an empty queue is blocked until another task receives or sends
+/// a message, or until its timeout is reached.
+///
+/// A woken task checks the queue again, so another task can take the message or the slot first.
+pub struct Queue<T> {
+    /// Only locked with disabled interrupts.
+    state: spin::Mutex<QueueState<T>>,
+}
+
+impl<T> Queue<T> {
+    /// Creates a new empty queue.
+    ///
+    /// # Arguments
+    /// * `capacity` - (usize) Maximum number of messages, at least `1`.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "message queue without capacity");
+        Queue {
+            state: spin::Mutex::new(QueueState {
+                messages: VecDeque::with_capacity(capacity),
+                capacity,
+                receivers: WaitQueue::new(),
+                senders: WaitQueue::new(),
+            }),
+        }
+    }
+
+    /// Sends a message. If the queue is full, the running task is blocked until a slot is free.
+    ///
+    /// # Arguments
+    /// * `message` - (T) The message.
+    pub fn send(&self, message: T) {
+        if self.send_until(message, usize::MAX).is_err() {
+            unreachable!("send without timeout failed");
+        }
+    }
+
+    /// Sends a message without blocking.
+    ///
+    /// # Arguments
+    /// * `message` - (T) The message.
+    ///
+    /// # Return
+    /// * `Result<(), T>` - The message is given back if the queue is full.
+    pub fn try_send(&self, message: T) -> Result<(), T> {
+        self.send_until(message, 0)
+    }
+
+    /// Receives a message. If the queue is empty, the running task is blocked until a message is
+    /// sent.
+    ///
+    /// # Return
+    /// * `T` - The oldest message of the queue.
+    pub fn receive(&self) -> T {
+        self.receive_until(usize::MAX)
+            .expect("receive without timeout failed")
+    }
+
+    /// Receives a message. If the queue is empty, the running task is blocked until a message is
+    /// sent or the timeout is reached.
+    ///
+    /// # Arguments
+    /// * `timeout` - (u64) Timeout in milliseconds.
+    ///
+    /// # Return
+    /// * `Option<T>` - The oldest message of the queue, `None` if the timeout was reached.
+    pub fn receive_timeout(&self, timeout: u64) -> Option<T> {
+        self.receive_until((rdtsc() as usize).saturating_add(ms_to_ticks(timeout)))
+    }
+
+    /// Sends a message, blocks the running task at most until the timestamp `deadline`.
+    fn send_until(&self, message: T, deadline: usize) -> Result<(), T> {
+        tasks::kernel_call(|| {
+            let pid = scheduler::current_pid();
+            let mut message = message;
+            loop {
+                let result = without_interrupts(|| {
+                    let mut state = self.state.lock();
+                    if state.messages.len() < state.capacity {
+                        state.messages.push_back(message);
+                        return Ok(state.receivers.wake_one().is_some());
+                    }
+                    if rdtsc() as usize >= deadline {
+                        return Err((message, true));
+                    }
+                    state.senders.push(pid);
+                    drop(state);
+                    if !scheduler::block_current_until(deadline) {
+                        self.state.lock().senders.remove(pid);
+                    }
+                    Err((message, false))
+                });
+                match result {
+                    Ok(woken) => {
+                        if woken {
+                            scheduler::reschedule();
+                        }
+                        return Ok(());
+                    }
+                    Err((m, true)) => return Err(m),
+                    Err((m, false)) => message = m,
+                }
+            }
+        })
+    }
+
+    /// Receives a message, blocks the running task at most until the timestamp `deadline`.
+    fn receive_until(&self, deadline: usize) -> Option<T> {
+        tasks::kernel_call(|| {
+            let pid = scheduler::current_pid();
+            loop {
+                let (message, woken, expired) = without_interrupts(|| {
+                    let mut state = self.state.lock();
+                    let message = state.messages.pop_front();
+                    if message.is_some() {
+                        let woken = state.senders.wake_one().is_some();
+                        return (message, woken, false);
+                    }
+                    if rdtsc() as usize >= deadline {
+                        return (None, false, true);
+                    }
+                    state.receivers.push(pid);
+                    drop(state);
+                    if !scheduler::block_current_until(deadline) {
+                        self.state.lock().receivers.remove(pid);
+                    }
+                    (None, false, false)
+                });
+                if woken {
+                    scheduler::reschedule();
+                }
+                if message.is_some() || expired {
+                    return message;
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,72 @@
+//! Counting semaphore.
+use features::without_interrupts;
+use scheduler;
+use spin;
+use sync::WaitQueue;
+use tasks;
+
+/// Counter and wait queue of a semaphore.
+struct SemaphoreState {
+    /// Number of available units.
+    count: usize,
+    /// Tasks which wait for a unit.
+    waiters: WaitQueue,
+}
+
+/// Counting semaphore. A task which calls `wait()` while no unit is available is blocked until
+/// another task calls `signal()`.
+pub struct Semaphore {
+    /// Only locked with disabled interrupts.
+    state: spin::Mutex<SemaphoreState>,
+}
+
+impl Semaphore {
+    /// Creates a new semaphore.
+    ///
+    /// # Arguments
+    /// * `count` - (usize) Number of initially available units.
+    pub fn new(count: usize) -> Self {
+        Semaphore {
+            state: spin::Mutex::new(SemaphoreState {
+                count,
+                waiters: WaitQueue::new(),
+            }),
+        }
+    }
+
+    /// Takes a unit. If no unit is available, the running task is blocked until a unit is passed
+    /// to it by `signal()`.
+    pub fn wait(&self) {
+        tasks::kernel_call(|| {
+            let pid = scheduler::current_pid();
+            without_interrupts(|| {
+                let mut state = self.state.lock();
+                if state.count > 0 {
+                    state.count -= 1;
+                    return;
+                }
+                state.waiters.push(pid);
+                drop(state);
+                scheduler::block_current();
+            });
+        })
+    }
+
+    /// Returns a unit. If a task is waiting, the unit is passed to the first waiting task, which
+    /// is woken up. The scheduler is called, so the woken task can preempt the running task.
+    pub fn signal(&self) {
+        tasks::kernel_call(|| {
+            let woken = without_interrupts(|| {
+                let mut state = self.state.lock();
+                let woken = state.waiters.wake_one().is_some();
+                if !woken {
+                    state.count += 1;
+                }
+                woken
+            });
+            if woken {
+                scheduler::reschedule();
+            }
+        })
+    }
+}
@@ -0,0 +1,189 @@
+//! Syscall interface of the user tasks.
+//! User tasks run in ring 3 in their own address space (see `tasks::TaskParams::user()`). They
+//! can't call kernel functions directly, instead they trigger `int 0x80` with the number of the
+//! syscall in `rax` and up to three arguments in `rdi`, `rsi` and `rdx`. The result is returned
+//! in `rax`. The supported syscalls are:
+//!
+//!     0. EXIT           -> Ends the task with the exit code in the first argument
+//!     1. YIELD          -> Calls the scheduler
+//!     2. SLEEP          -> Sleeps for the given milliseconds
+//!     3. WRITE          -> Prints a buffer of the stack of the task on the screen
+//!     4. QUEUE_SEND     -> Sends a message to one of the user queues, blocks if it is full
+//!     5. QUEUE_RECEIVE  -> Receives a message from one of the user queues, blocks if it is empty
+//!
+//! Invalid syscalls and arguments return `SYSCALL_ERROR`, so it can't be sent as a message. The
+//! wrappers for the user tasks are in `user`.
+use core::slice;
+use core::str;
+use features::{msleep, without_interrupts};
+use memory::{MemoryController, PAGE_SIZE};
+use scheduler::{self, RUNNING_TASK};
+use sync;
+use tasks;
+
+pub mod user;
+
+/// Ends the running task.
+pub const EXIT: usize = 0;
+/// Gives up the cpu until the task is scheduled again.
+pub const YIELD: usize = 1;
+/// Sleeps for the given milliseconds.
+pub const SLEEP: usize = 2;
+/// Prints a buffer of the stack of the task.
+pub const WRITE: usize = 3;
+/// Sends a message to a user queue.
+pub const QUEUE_SEND: usize = 4;
+/// Receives a message from a user queue.
+pub const QUEUE_RECEIVE: usize = 5;
+
+/// Result of an invalid syscall.
+pub const SYSCALL_ERROR: usize = !0;
+
+/// Number of queues which can be used by user tasks.
+pub const USER_QUEUES: usize = 4;
+/// Maximum number of messages in a user queue.
+const USER_QUEUE_CAPACITY: usize = 16;
+/// Maximum number of bytes which are printed by one `WRITE`.
+const MAX_WRITE_LENGTH: usize = 256;
+
+/// A syscall gets the three arguments of the task and returns the result for `rax`.
+type Syscall = fn(usize, usize, usize) -> usize;
+
+/// Syscalls ordered by their number.
+static SYSCALLS: [Syscall; 6] = [
+    sys_exit,
+    sys_yield,
+    sys_sleep,
+    sys_write,
+    sys_queue_send,
+    sys_queue_receive,
+];
+
+lazy_static! {
+    /// Queues of the user tasks, the messages are single words.
+    static ref QUEUES: [sync::Queue<usize>; USER_QUEUES] = [
+        sync::Queue::new(USER_QUEUE_CAPACITY),
+        sync::Queue::new(USER_QUEUE_CAPACITY),
+        sync::Queue::new(USER_QUEUE_CAPACITY),
+        sync::Queue::new(USER_QUEUE_CAPACITY),
+    ];
+}
+
+extern "C" {
+    /// Start of the section with the code of the user tasks, defined by the linker.
+    static __start_user_text: u8;
+    /// End of the section with the code of the user tasks, defined by the linker.
+    static __stop_user_text: u8;
+}
+
+/// Allows user tasks to execute the code of the section `user_text` (see `user`). The linker script
+/// aligns the start and end of the section to pages, so no kernel code becomes accessible.
+///
+/// # Arguments
+/// * `memory_controller` - (&mut MemoryController) Used to change the kernel table.
+pub fn init(memory_controller: &mut MemoryController) {
+    let (start, end) = unsafe {
+        (
+            &__start_user_text as *const u8 as usize,
+            &__stop_user_text as *const u8 as usize,
+        )
+    };
+    assert!(
+        start % PAGE_SIZE == 0 && end % PAGE_SIZE == 0,
+        "user_text 0x{:x} - 0x{:x} is not page aligned",
+        start,
+        end
+    );
+    memory_controller.set_user_accessible(start, end);
+    trace_info!("user code 0x{:x} - 0x{:x}", start, end);
+}
+
+/// Executes a syscall, called by the syscall interrupt handler in the kernel address space.
+///
+/// # Arguments
+/// * `number` - (usize) Number of the syscall.
+/// * `first` - (usize) First argument.
+/// * `second` - (usize) Second argument.
+/// * `third` - (usize) Third argument.
+///
+/// # Return
+/// * `usize` - Result of the syscall, `SYSCALL_ERROR` if the syscall does not exist.
+pub fn dispatch(number: usize, first: usize, second: usize, third: usize) -> usize {
+    match SYSCALLS.get(number) {
+        Some(syscall) => syscall(first, second, third),
+        None => {
+            trace_warn!(
+                "invalid syscall {} of task {}",
+                number,
+                scheduler::current_pid()
+            );
+            SYSCALL_ERROR
+        }
+    }
+}
+
+fn sys_exit(code: usize, _: usize, _: usize) -> usize {
+    tasks::exit(code as isize);
+}
+
+fn sys_yield(_: usize, _: usize, _: usize) -> usize {
+    scheduler::reschedule();
+    0
+}
+
+/// Sleeps for `ms` milliseconds. `msleep()` saturates, so a huge time means forever.
+fn sys_sleep(ms: usize, _: usize, _: usize) -> usize {
+    msleep(ms as u64);
+    0
+}
+
+/// Prints `length` bytes at `address` as text. The buffer must be on the stack of the task, the
+/// other memory of the task is not readable by it anyway.
+fn sys_write(address: usize, length: usize, _: usize) -> usize {
+    let stack = without_interrupts(|| unsafe {
+        RUNNING_TASK
+            .lock()
+            .stack
+            .as_ref()
+            .map(|stack| (stack.bottom(), stack.top()))
+    });
+    let valid = match stack {
+        Some((bottom, top)) => {
+            length <= MAX_WRITE_LENGTH
+                && address >= bottom
+                && address.checked_add(length).map_or(false, |end| end <= top)
+        }
+        None => false,
+    };
+    if !valid {
+        return SYSCALL_ERROR;
+    }
+    let bytes = unsafe { slice::from_raw_parts(address as *const u8, length) };
+    match str::from_utf8(bytes) {
+        Ok(text) => {
+            print!("{}", text);
+            length
+        }
+        Err(_) => SYSCALL_ERROR,
+    }
+}
+
+/// Sends `message` to a user queue. `SYSCALL_ERROR` is not a valid message, because the receiver
+/// could not tell it from an error.
+fn sys_queue_send(index: usize, message: usize, _: usize) -> usize {
+    match QUEUES.get(index) {
+        Some(_) if message == SYSCALL_ERROR => SYSCALL_ERROR,
+        Some(queue) => {
+            queue.send(message);
+            0
+        }
+        None => SYSCALL_ERROR,
+    }
+}
+
+fn sys_queue_receive(index: usize, _: usize, _: usize) -> usize {
+    match QUEUES.get(index) {
+        Some(queue) => queue.receive(),
+        None => SYSCALL_ERROR,
+    }
+}
@@ -0,0 +1,157 @@
+//! Library of the user tasks.
+//! User tasks only see the code of the section `user_text` and their own stack, so all functions
+//! which they call must be placed in this section with `#[link_section = "user_text"]`. This also
+//! excludes most of `core`: functions which are not inlined, `core::fmt`, constants in the read
+//! only data of the kernel and panics. A user task which leaves its section is killed.
+//!
+//! The entry function of a user task must be a `TaskEntry` in this section. It is called by
+//! `user_start()`, which exits the task with the returned exit code.
+use super::{EXIT, QUEUE_RECEIVE, QUEUE_SEND, SLEEP, SYSCALL_ERROR, WRITE};
+use tasks::TaskEntry;
+
+/// Triggers a syscall (see `syscall`).
+///
+/// # Arguments
+/// * `number` - (usize) Number of the syscall.
+/// * `first` - (usize) First argument.
+/// * `second` - (usize) Second argument.
+/// * `third` - (usize) Third argument.
+///
+/// # Return
+/// * `usize` - Result of the syscall.
+#[inline(never)]
+#[link_section = "user_text"]
+pub fn syscall(number: usize, first: usize, second: usize, third: usize) -> usize {
+    let result: usize;
+    unsafe {
+        asm!("int 0x80"
+            : "={rax}"(result)
+            : "{rax}"(number), "{rdi}"(first), "{rsi}"(second), "{rdx}"(third)
+            : "memory"
+            : "intel", "volatile");
+    }
+    result
+}
+
+/// Ends the user task.
+///
+/// # Arguments
+/// * `code` - (isize) Exit code of the task.
+#[link_section = "user_text"]
+pub fn exit(code: isize) -> ! {
+    syscall(EXIT, code as usize, 0, 0);
+    loop {}
+}
+
+/// Sleeps for the given time.
+///
+/// # Arguments
+/// * `ms` - (usize) Time in milliseconds.
+#[link_section = "user_text"]
+pub fn sleep(ms: usize) {
+    syscall(SLEEP, ms, 0, 0);
+}
+
+/// Prints text on the screen. The text must be on the stack of the task.
+///
+/// # Arguments
+/// * `address` - (*const u8) Start of the text, encoded as utf-8.
+/// * `length` - (usize) Length of the text in bytes.
+///
+/// # Return
+/// * `usize` - The number of printed bytes, `SYSCALL_ERROR` if the text is invalid.
+#[link_section = "user_text"]
+pub fn write(address: *const u8, length: usize) -> usize {
+    syscall(WRITE, address as usize, length, 0)
+}
+
+/// Sends a message to a user queue. Blocks while the queue is full.
+///
+/// # Arguments
+/// * `queue` - (usize) Index of the queue.
+/// * `message` - (usize) The message, must not be `SYSCALL_ERROR`.
+///
+/// # Return
+/// * `usize` - `0`, `SYSCALL_ERROR` if the queue does not exist or the message is invalid.
+#[link_section = "user_text"]
+pub fn queue_send(queue: usize, message: usize) -> usize {
+    syscall(QUEUE_SEND, queue, message, 0)
+}
+
+/// Receives a message from a user queue. Blocks while the queue is empty.
+///
+/// # Arguments
+/// * `queue` - (usize) Index of the queue.
+///
+/// # Return
+/// * `usize` - The message, `SYSCALL_ERROR` if the queue does not exist.
+#[link_section = "user_text"]
+pub fn queue_receive(queue: usize) -> usize {
+    syscall(QUEUE_RECEIVE, queue, 0, 0)
+}
+
+/// First function of a user task, started in ring 3 with the arguments in `rdi` and `rsi`.
+/// Returning from `entry` exits the task.
+///
+/// # Arguments
+/// * `entry` - (TaskEntry) The function of the task, in the section `user_text`.
+/// * `argument` - (usize) Argument for `entry`.
+#[link_section = "user_text"]
+pub extern "C" fn user_start(entry: TaskEntry, argument: usize) -> ! {
+    let code = entry(argument);
+    exit(code);
+}
+
+/// Example of a user task, which sends the number of seconds since its start to the user queue `0`,
+/// up to 10. The numbers are printed by `printer()`. Started by the shell command *user*.
+///
+/// # Arguments
+/// * `_argument` - (usize) Not used.
+#[link_section = "user_text"]
+pub fn counter(_argument: usize) -> isize {
+    let mut seconds = 0;
+    loop {
+        if queue_send(0, seconds) == SYSCALL_ERROR {
+            return 1;
+        }
+        if seconds == 10 {
+            return 0;
+        }
+        sleep(1000);
+        seconds += 1;
+    }
+}
+
+/// Example of a user task, which receives the numbers of `counter()` from the user queue `0` and
+/// prints them, until it receives 10. The digits are written into a buffer on the stack, because
+/// the task can't use `core::fmt`. Started by the shell command *user*.
+///
+/// # Arguments
+/// * `_argument` - (usize) Not used.
+#[link_section = "user_text"]
+pub fn printer(_argument: usize) -> isize {
+    let mut buffer = [0u8; 24];
+    let start = &mut buffer as *mut [u8; 24] as usize;
+    loop {
+        let number = queue_receive(0);
+        if number == SYSCALL_ERROR {
+            return 1;
+        }
+        // the digits are written backwards, followed by a line break
+        let mut position = 24 - 1;
+        unsafe { *((start + position) as *mut u8) = b'\n' };
+        let mut value = number;
+        loop {
+            position -= 1;
+            unsafe { *((start + position) as *mut u8) = b'0' + (value % 10) as u8 };
+            value /= 10;
+            if value == 0 {
+                break;
+            }
+        }
+        write((start + position) as *const u8, 24 - position);
+        if number == 10 {
+            return 0;
+        }
+    }
+}
@@ -0,0 +1,204 @@
+//! Handles of spawned tasks.
+//! `spawn()` returns a `TaskHandle`, which can be used to wait for the end of the task (`join()`)
+//! or to kill it. The exit code of a task is stored in an exit record until it is joined. The
+//! records are written by the scheduler when it removes a finished task (see `exited()`), so
+//! tasks which are killed by the deadline check are covered too.
+//!
+//! Dropping a handle detaches the task, its record is removed as soon as it exits.
+use alloc::Vec;
+use features::without_interrupts;
+use scheduler::{self, admission};
+use spin::Mutex;
+use tasks::{self, TaskStatus, NEW_TASKS};
+
+/// Exit code of a task which was killed.
+pub const KILLED: isize = -1;
+/// Exit code of a task which was not started, because its stack or address space could not be
+/// allocated.
+pub const NO_MEMORY: isize = -2;
+
+/// Exit state of a spawned task.
+struct ExitRecord {
+    /// Pid of the task.
+    pid: usize,
+    /// Exit code, `None` while the task is running.
+    code: Option<isize>,
+    /// Pid of the task which waits in `join()`.
+    joiner: Option<usize>,
+    /// Set if the handle was dropped, the record is removed when the task exits.
+    detached: bool,
+}
+
+lazy_static! {
+    /// Exit records of all spawned tasks which are not joined or detached. Only locked with
+    /// disabled interrupts.
+    static ref EXITS: Mutex<Vec<ExitRecord>> = Mutex::new(vec![]);
+}
+
+/// Handle of a spawned task, returned by `spawn()`.
+#[derive(Debug)]
+pub struct TaskHandle {
+    pid: usize,
+}
+
+impl TaskHandle {
+    /// Returns the pid of the task.
+    pub fn pid(&self) -> usize {
+        self.pid
+    }
+
+    /// Waits until the task exits. The running task is blocked meanwhile.
+    ///
+    /// # Return
+    /// * `isize` - Exit code of the task, `KILLED` if it was killed, `NO_MEMORY` if it could not be
+    /// started.
+    pub fn join(self) -> isize {
+        let current = scheduler::current_pid();
+        assert!(current != self.pid, "task joins itself");
+        without_interrupts(|| {
+            let waiting = {
+                let mut exits = EXITS.lock();
+                let record = exits
+                    .iter_mut()
+                    .find(|record| record.pid == self.pid)
+                    .expect("exit record missing");
+                if record.code.is_none() {
+                    record.joiner = Some(current);
+                    true
+                } else {
+                    false
+                }
+            };
+            if waiting {
+                scheduler::block_current();
+            }
+            self.take_code().expect("joined task did not exit")
+        })
+    }
+
+    /// Returns the exit code without blocking.
+    ///
+    /// # Return
+    /// * `Option<isize>` - Exit code of the task, `None` if it is still running.
+    pub fn try_join(&self) -> Option<isize> {
+        without_interrupts(|| {
+            EXITS
+                .lock()
+                .iter()
+                .find(|record| record.pid == self.pid)
+                .and_then(|record| record.code)
+        })
+    }
+
+    /// Kills the task with the exit code `KILLED`. A task which is not started yet is removed
+    /// from `NEW_TASKS`. The scheduler releases the stack resources and priority boosts of the
+    /// task, so the system ceiling drops again. The kernel mutexes of the task are passed to their
+    /// waiting tasks or unlocked, their data may be left inconsistent.
+    pub fn kill(&self) {
+        if self.pid == scheduler::current_pid() {
+            tasks::exit(KILLED);
+        }
+        if discard(self.pid, KILLED) {
+            return;
+        }
+        scheduler::with_task(self.pid, |task| {
+            if task.status != TaskStatus::FINISHED {
+                task.exit_code = KILLED;
+                task.status = TaskStatus::FINISHED;
+            }
+        });
+    }
+
+    /// Removes the record of the task if the task exited.
+    fn take_code(&self) -> Option<isize> {
+        let mut exits = EXITS.lock();
+        let index = exits
+            .iter()
+            .position(|record| record.pid == self.pid && record.code.is_some())?;
+        exits.remove(index).code
+    }
+}
+
+impl Drop for TaskHandle {
+    /// Detaches the task.
+    fn drop(&mut self) {
+        without_interrupts(|| {
+            if self.take_code().is_some() {
+                return;
+            }
+            let mut exits = EXITS.lock();
+            for record in exits.iter_mut().filter(|record| record.pid == self.pid) {
+                record.detached = true;
+            }
+        });
+    }
+}
+
+/// Creates the exit record of a new task and returns its handle.
+///
+/// # Arguments
+/// * `pid` - (usize) Pid of the new task.
+pub fn register(pid: usize) -> TaskHandle {
+    without_interrupts(|| {
+        EXITS.lock().push(ExitRecord {
+            pid,
+            code: None,
+            joiner: None,
+            detached: false,
+        })
+    });
+    TaskHandle { pid }
+}
+
+/// Removes a task which is not started yet from `NEW_TASKS` and ends it with an exit code, e.g.
+/// because it was killed. The utilization of a periodic task is released.
+///
+/// # Arguments
+/// * `pid` - (usize) Pid of the task.
+/// * `code` - (isize) Exit code which is passed to the handle.
+///
+/// # Return
+/// * `bool` - `false` if the task is not in `NEW_TASKS`, e.g. because it was already started.
+pub fn discard(pid: usize, code: isize) -> bool {
+    let not_started = without_interrupts(|| {
+        let mut new_tasks = NEW_TASKS.lock();
+        let index = new_tasks.iter().position(|task| task.pid == pid)?;
+        Some(new_tasks.remove(index))
+    });
+    match not_started {
+        Some(task) => {
+            if task.params.period != 0 {
+                admission::release(task.params.wcet, task.params.period, task.params.deadline);
+            }
+            without_interrupts(|| exited(pid, code));
+            true
+        }
+        None => false,
+    }
+}
+
+/// Stores the exit code of a task and wakes up the task which joins it. Called by the scheduler
+/// after a finished task was removed and the task lists are unlocked. Must be called with disabled
+/// interrupts. Tasks without record (e.g. started by `sched_init()`) are ignored.
+///
+/// # Arguments
+/// * `pid` - (usize) Pid of the task.
+/// * `code` - (isize) Exit code of the task.
+pub fn exited(pid: usize, code: isize) {
+    let joiner = {
+        let mut exits = EXITS.lock();
+        let index = match exits.iter().position(|record| record.pid == pid) {
+            Some(index) => index,
+            None => return,
+        };
+        if exits[index].detached {
+            exits.remove(index);
+            return;
+        }
+        exits[index].code = Some(code);
+        exits[index].joiner
+    };
+    if let Some(joiner) = joiner {
+        scheduler::unblock(joiner);
+    }
+}
@@ -2,25 +2,80 @@
 //! The *callback* functions of all scheduled tasks are defined here. So if developers want to enhance the system with
 //! new tasks, the function which is called by the scheduler when executing the task should be defined here.
 //! Specific functions used by a task should be defined in external modules/crates and should only be called from here.
-//! This module contains also some helper functions to manage tasks, e.g. `increment_pid()` or `finish_task()`.
+//! This module contains also some helper functions to manage tasks, e.g. `increment_pid()` or `exit()`.
+//! New tasks are started with `spawn()`, which returns a `TaskHandle` (see `handle`).
 
 
 use alloc::string::String;
 use alloc::Vec;
+use core::str;
+use core::usize;
 use features::keyboard;
-use features::{msleep, shell::*, test_bit};
-use scheduler::RUNNING_TASK;
+use features::{
+    active_sleep, ms_to_ticks, msleep, shell::*, sleep_until, test_bit, wait_next_period,
+    without_interrupts,
+};
+use interrupts;
+use lazy_static;
+use memory::{self, AddressSpace, Stack};
+use scheduler::admission::{self, AdmissionError};
+use scheduler::context;
+use scheduler::deadline::{self, OverrunPolicy};
+use scheduler::timer::{self, TimerHandle, TimerMode};
 use scheduler::TASKS;
+use scheduler::{self, RUNNING_TASK};
 use spin::Mutex;
+use sync::{self, PriorityBoosts, Protocol, WaitMode};
+use syscall::user;
 use vga_buffer;
 use vga_buffer::Color;
 use x86_64;
 use x86_64::instructions::rdtsc;
+use x86_64::registers::control_regs;
 use x86_64::VirtualAddress;
 
+mod handle;
+
+pub use self::handle::{discard, exited, TaskHandle, KILLED, NO_MEMORY};
+
 /// Stores the highest process id.
 static mut PID_COUNTER: usize = 0;
 
+/// Number of priority levels of the fixed priority scheduler.
+pub const PRIORITY_LEVELS: usize = 32;
+/// Priority of the idle task, the lowest priority.
+pub const IDLE_PRIORITY: u8 = 0;
+/// Priority of tasks which are started without a priority.
+pub const DEFAULT_PRIORITY: u8 = 1;
+/// Number of stack pages of tasks which are started by the shell.
+pub const DEFAULT_STACK_PAGES: usize = 4;
+/// Number of pages of the kernel stack of user tasks.
+pub const KERNEL_STACK_PAGES: usize = 2;
+
+/// Ceiling of the resources which are shared by the tasks (`PIECE`, `BOARD` and `SHELL`). This is
+/// the preemption level of the keyboard task, the highest level of all tasks, so no task which
+/// uses them can preempt a holder.
+const SHARED_CEILING: u8 = 4;
+
+/// Maximum number of key events which are not yet handled by the shell.
+const KEY_EVENTS_CAPACITY: usize = 16;
+
+/// Blink interval of the shell cursor in milliseconds.
+const CURSOR_BLINK_INTERVAL: u64 = 500;
+/// Ceiling of the `Protocol::Ceiling` mutex of `inversion_demo()`, the priority of its high
+/// priority task.
+const INVERSION_CEILING: u8 = 3;
+/// Event of `INVERSION_EVENTS`, set by the low priority task when it holds the mutex.
+const INVERSION_LOCKED: usize = 1;
+/// Event of `INVERSION_EVENTS`, set by the low priority task when it is done.
+const INVERSION_LOW_DONE: usize = 2;
+/// Event of `INVERSION_EVENTS`, set by the medium priority task when it is done.
+const INVERSION_MEDIUM_DONE: usize = 4;
+/// Event of `TIMER_EVENTS`, set by the auto-reload timer of `timer_demo()`.
+const TIMER_TICK: usize = 1;
+/// Event of `TIMER_EVENTS`, set by the one-shot timer of `timer_demo()`.
+const TIMER_SLOW_DOWN: usize = 2;
+
 /// Tells the scheduler if a task was started by the shell.
 pub static mut TASK_STARTED: bool = false;
 
@@ -38,8 +93,9 @@ const ROW_OFFSET: u8 = 2;
 const COL_OFFSET: u8 = 50;
 
 lazy_static! {
-/// The currently falling piece
-    pub static ref PIECE: Mutex<Piece> = Mutex::new(Piece {
+/// The currently falling piece. `PIECE`, `BOARD` and `SHELL` are shared by tasks with different
+/// priorities, so they are stack resources with the ceiling `SHARED_CEILING`.
+    pub static ref PIECE: sync::Mutex<Piece> = sync::Mutex::with_protocol(Piece {
         //The previous position of the piece
         oldx: (BOARD_WIDTH / 2) as i8,
         posx: (BOARD_WIDTH / 2) as i8,
@@ -52,17 +108,205 @@ lazy_static! {
         oldshape: vec![vec![0]],
         //The current shape of the piece
         shape: vec![vec![0]],
-    });
+    }, Protocol::StackResource(SHARED_CEILING));
     /// Global board, contains the occupied cells.
-    pub static ref BOARD: Mutex<Board> = Mutex::new(Board {
+    pub static ref BOARD: sync::Mutex<Board> = sync::Mutex::with_protocol(Board {
         cells: [[None; BOARD_WIDTH as usize]; BOARD_HEIGHT as usize],
-    });
-    /// Vector to store new tasks. New tasks can be started by the shell or other tasks. The main task
-    /// starts new tasks from this vector.
-    pub static ref NEW_TASKS: Mutex<Vec<VirtualAddress>> = Mutex::new(vec![]);
+    }, Protocol::StackResource(SHARED_CEILING));
+    /// Vector to store new tasks. New tasks can be started by the shell or other tasks with
+    /// `spawn()`. The main task starts new tasks from this vector.
+    pub static ref NEW_TASKS: Mutex<Vec<NewTask>> = Mutex::new(vec![]);
+    /// Stacks of finished tasks, added by the scheduler. The main task frees them. Only locked
+    /// with disabled interrupts.
+    pub static ref FINISHED_STACKS: Mutex<Vec<Stack>> = Mutex::new(vec![]);
+    /// Address spaces of finished isolated tasks, added by the scheduler. The main task frees them.
+    /// Only locked with disabled interrupts.
+    pub static ref FINISHED_ADDRESS_SPACES: Mutex<Vec<AddressSpace>> = Mutex::new(vec![]);
 
     /// The global shell object
-    pub static ref SHELL: Mutex<Shell> = Mutex::new(Shell::new((21, 11)));
+    pub static ref SHELL: sync::Mutex<Shell> = sync::Mutex::with_protocol(
+        Shell::new((21, 11)),
+        Protocol::StackResource(SHARED_CEILING),
+    );
+
+    /// Key events of the keyboard task, received by the shell task.
+    pub static ref KEY_EVENTS: sync::Queue<String> = sync::Queue::new(KEY_EVENTS_CAPACITY);
+
+    /// Allows only one `isolated_counter()` at a time, because they share a row of the screen. The
+    /// other counters wait until it is finished.
+    static ref ISOLATED_COUNTER: sync::Semaphore = sync::Semaphore::new(1);
+
+    /// Mutexes of `inversion_demo()`, one for each protocol against priority inversion. The data
+    /// counts how often the mutex was held.
+    static ref INVERSION_MUTEXES: [sync::Mutex<usize>; 2] = [
+        sync::Mutex::with_protocol(0, Protocol::Inheritance),
+        sync::Mutex::with_protocol(0, Protocol::Ceiling(INVERSION_CEILING)),
+    ];
+    /// Events of the tasks of `inversion_demo()`.
+    static ref INVERSION_EVENTS: sync::EventGroup = sync::EventGroup::new();
+
+    /// Events of `timer_demo()`, set by the callbacks of its timers.
+    static ref TIMER_EVENTS: sync::EventGroup = sync::EventGroup::new();
+    /// Timers of the running `timer_demo()`. Allows only one demo at a time, because they share a
+    /// cell of the screen.
+    static ref TIMER_DEMO: sync::Mutex<Option<(TimerHandle, TimerHandle)>> =
+        sync::Mutex::new(None);
+}
+
+/// Function which is executed by a spawned task. It gets the argument given to `spawn()`, the
+/// returned value is the exit code of the task.
+pub type TaskEntry = fn(usize) -> isize;
+
+/// Timing parameters and priority of a new task.
+#[derive(Debug, Clone, Copy)]
+pub struct TaskParams {
+    /// Period in tsc ticks, `0` for aperiodic tasks.
+    pub period: usize,
+    /// Relative deadline in tsc ticks, `0` for tasks without deadline.
+    pub deadline: usize,
+    /// Worst case execution time of one job in tsc ticks.
+    pub wcet: usize,
+    /// Priority for the fixed priority scheduler, also used as preemption level.
+    pub priority: u8,
+    /// Runs the task in its own address space, see `isolated()`.
+    pub isolated: bool,
+    /// Runs the task in ring 3, see `user()`.
+    pub user: bool,
+}
+
+impl TaskParams {
+    /// Parameters of an aperiodic task. Aperiodic tasks have no deadline and are always admitted.
+    ///
+    /// # Arguments
+    /// * `priority` - (u8) Priority for the fixed priority scheduler.
+    pub fn aperiodic(priority: u8) -> Self {
+        TaskParams {
+            period: 0,
+            deadline: 0,
+            wcet: 0,
+            priority,
+            isolated: false,
+            user: false,
+        }
+    }
+
+    /// Parameters of a periodic task, which is checked by the admission control.
+    ///
+    /// # Arguments
+    /// * `period` - (usize) Period in tsc ticks.
+    /// * `deadline` - (usize) Relative deadline in tsc ticks, `0` if the deadline equals the period.
+    /// * `wcet` - (usize) Worst case execution time of one job in tsc ticks.
+    /// * `priority` - (u8) Priority for the fixed priority scheduler, e.g. a RM or DM assignment. It
+    /// is also used as preemption level for the Stack Resource Policy.
+    pub fn periodic(period: usize, deadline: usize, wcet: usize, priority: u8) -> Self {
+        TaskParams {
+            period,
+            deadline: if deadline == 0 { period } else { deadline },
+            wcet,
+            priority,
+            isolated: false,
+            user: false,
+        }
+    }
+
+    /// Runs the task in its own address space, which only contains the kernel and the stack of
+    /// the task. The heap is not mapped, so the task must not use it (see `forbid_heap()`). An
+    /// access to the heap or to another stack kills the task instead of corrupting them. Kernel
+    /// functions which use the heap must be called with `kernel_call()`, the objects of `sync`
+    /// already do this.
+    pub fn isolated(mut self) -> Self {
+        self.isolated = true;
+        self
+    }
+
+    /// Runs the task in ring 3 in its own address space. The task can only use its stack and the
+    /// code of the user section, it calls the kernel with syscalls (see `syscall::user`). The task
+    /// gets an additional kernel stack for interrupts and syscalls.
+    pub fn user(mut self) -> Self {
+        self.isolated = true;
+        self.user = true;
+        self
+    }
+}
+
+/// Describes a task which is started by the main task. Periodic tasks are already admitted by the
+/// admission control when they are pushed to `NEW_TASKS`.
+#[derive(Debug, Clone)]
+pub struct NewTask {
+    /// *Name* of the task.
+    pub name: char,
+    /// Pid of the task, already known by its `TaskHandle`.
+    pub pid: usize,
+    /// The function which is executed by the task.
+    pub entry: TaskEntry,
+    /// Argument for `entry`.
+    pub argument: usize,
+    /// Number of pages of the stack.
+    pub stack_pages: usize,
+    /// Timing parameters and priority.
+    pub params: TaskParams,
+}
+
+impl NewTask {
+    /// Creates the `TaskData` of the task. The task starts in `task_start()`, which calls `entry`
+    /// and exits the task with the returned exit code. A user task starts in
+    /// `syscall::user::user_start()` in ring 3, its context is saved on its kernel stack.
+    ///
+    /// # Arguments
+    /// * `stack` - (Stack) The allocated stack, freed when the task is finished.
+    /// * `kernel_stack` - (Option<Stack>) Kernel stack of a user task, `None` for other tasks.
+    /// * `address_space` - (Option<AddressSpace>) Address space of an isolated task, `None` if
+    /// the task uses the address space of the kernel.
+    ///
+    /// # Return
+    /// * TaskData - New created `TaskData` with status `READY`.
+    pub fn create(
+        &self,
+        stack: Stack,
+        kernel_stack: Option<Stack>,
+        address_space: Option<AddressSpace>,
+    ) -> TaskData {
+        let (context_top, start) = match kernel_stack {
+            Some(ref kernel_stack) => (kernel_stack.top(), user::user_start as usize),
+            None => (stack.top(), task_start as usize),
+        };
+        let mut task = TaskData::with_pid(
+            self.pid,
+            self.name,
+            0,
+            VirtualAddress(context_top),
+            VirtualAddress(start),
+            TaskStatus::READY,
+        );
+        unsafe {
+            context::set_arguments(task.stack_pointer, self.entry as u64, self.argument as u64);
+            if kernel_stack.is_some() {
+                let (code_segment, stack_segment) = interrupts::user_segments();
+                context::set_user_mode(
+                    task.stack_pointer,
+                    VirtualAddress(stack.top()),
+                    code_segment,
+                    stack_segment,
+                );
+            }
+        }
+        task.stack = Some(stack);
+        task.kernel_stack = kernel_stack;
+        if let Some(space) = address_space {
+            unsafe {
+                context::set_address_space(task.stack_pointer, space.p4_address());
+            }
+            // the heap is not mapped in the address space
+            task.heap_forbidden = true;
+            task.address_space = Some(space);
+        }
+        task.period = self.params.period;
+        task.deadline = self.params.deadline;
+        task.wcet = self.params.wcet;
+        task.set_base_priority(self.params.priority);
+        task.preemption_level = self.params.priority;
+        task
+    }
 }
 
 /// Struct of the currently falling piece
@@ -494,7 +738,7 @@ impl Board {
     }
 }
 
-/// Used to represent the current task status. In this system four different status are used.
+/// Used to represent the current task status. In this system six different status are used.
 #[derive(Debug, Clone, PartialEq)]
 pub enum TaskStatus {
     /// Used for the idle task
@@ -503,12 +747,20 @@ pub enum TaskStatus {
     READY,
     /// Used for all active tasks in the system
     RUNNING,
+    /// Used for a task which waits until its `sleep_ticks` are reached. Waking up releases a new job.
+    SLEEPING,
+    /// Used for a task which waits for a kernel object (see `sync`). It is only scheduled again
+    /// after it is woken up by `scheduler::unblock()` or its `block_timeout` is reached.
+    BLOCKED,
     /// Used when a task is terminated to show the scheduler that this task can be removed from the
     /// task list.
     FINISHED,
 }
 
 /// Stores all relevant data of a task. Each task gets an own `TaskData`.
+///
+/// All times (`sleep_ticks`, `period`, `deadline`, ...) are measured in tsc ticks. Use
+/// `features::ms_to_ticks()` to convert milliseconds.
 #[derive(Debug, Clone)]
 pub struct TaskData {
     /// For simplification only a char is used to represent the name of the task. Strings are causing
@@ -517,11 +769,14 @@ pub struct TaskData {
     /// Identification of a task. The main Task starts by 1, each new task will increment this value
     /// by 1.
     pub pid: usize,
-    /// Stores the `cpu_flags` for scheduling.
+    /// Stores the `cpu_flags` of the task when it was interrupted. Only used for information, the
+    /// flags are restored from the saved context.
     pub cpu_flags: u64,
-    /// Stores the `stack_pointer` for scheduling.
+    /// Stores the `stack_pointer` for scheduling. Points to the saved `Context` of the task (see
+    /// `scheduler::context`).
     pub stack_pointer: VirtualAddress,
-    /// Stores the `instruction_pointer` for scheduling.
+    /// Stores the `instruction_pointer` of the task when it was interrupted. Only used for
+    /// information, the instruction pointer is restored from the saved context.
     pub instruction_pointer: VirtualAddress,
     /// Stores the `TaskStatus` to show the scheduler the status of a task
     pub status: TaskStatus,
@@ -533,16 +788,63 @@ pub struct TaskData {
     pub time_active: usize,
     /// Used for logging / `htop`. Stores a delta value for calculation.
     pub last_time_stamp: usize,
+    /// Period of the task. `0` is used for aperiodic tasks.
+    pub period: usize,
+    /// Deadline of each job relative to its release. `0` is used for tasks without a deadline.
+    pub deadline: usize,
+    /// Worst case execution time (budget) of one job.
+    pub wcet: usize,
+    /// Timestamp at which the current job was released.
+    pub release_time: usize,
+    /// Absolute deadline of the current job, used by the EDF scheduler. `usize::MAX` if the task
+    /// has no deadline.
+    pub absolute_deadline: usize,
+    /// Number of jobs which missed their deadline.
+    pub deadline_misses: usize,
+    /// Set if the current job already missed its deadline, so it is only counted once.
+    pub job_missed: bool,
+    /// Set by `OverrunPolicy::SkipNextJob`. The next release of the task is skipped.
+    pub skip_next_job: bool,
+    /// Defines what happens if the task misses a deadline.
+    pub overrun_policy: OverrunPolicy,
+    /// Priority for the fixed priority scheduler. A higher value means a higher priority, the
+    /// maximum is `PRIORITY_LEVELS - 1`. While the task holds a kernel mutex, the priority can be
+    /// boosted above `base_priority` (see `sync::Protocol`).
+    pub priority: u8,
+    /// Priority which was assigned to the task, without boosts.
+    pub base_priority: u8,
+    /// Priority boosts of the kernel mutexes held by the task.
+    pub boosts: PriorityBoosts,
+    /// Preemption level for the Stack Resource Policy (see `scheduler::srp`). Tasks with shorter
+    /// relative deadlines need higher levels, by default the priority given at start is used.
+    pub preemption_level: u8,
+    /// Timestamp at which a `BLOCKED` task is woken up, `usize::MAX` if it waits without timeout.
+    pub block_timeout: usize,
+    /// Set if the task was woken up because its `block_timeout` was reached.
+    pub timed_out: bool,
+    /// Exit code of a `FINISHED` task, passed to its `TaskHandle`.
+    pub exit_code: isize,
+    /// Stack of the task, `None` for the main task. It is freed when the task is finished.
+    pub stack: Option<Stack>,
+    /// Set by `forbid_heap()`. Heap allocations while the task runs cause a panic.
+    pub heap_forbidden: bool,
+    /// Address space of an isolated task, `None` if the task uses the address space of the
+    /// kernel. It is freed when the task is finished.
+    pub address_space: Option<AddressSpace>,
+    /// Kernel stack of a user task, used by interrupts and syscalls. It is freed when the task is
+    /// finished.
+    pub kernel_stack: Option<Stack>,
 }
 
 impl TaskData {
-    /// Creates a new `TaskData`.
+    /// Creates a new `TaskData`. The initial context of the task is prepared at the top of its
+    /// stack, so the stack must already be mapped.
     ///
     /// # Arguments
     /// * `name` - (char) *Name* of the taks. Currently only a char (see description above).
     /// * `cpu_flags` - (u64) cpu flags.
-    /// * `stack_pointer` - (VirtualAddress)
-    /// * `instruction_pointer` - (VirtualAddress)
+    /// * `stack_pointer` - (VirtualAddress) Top address of the stack.
+    /// * `instruction_pointer` - (VirtualAddress) The function which is executed by the task.
     /// * `status` - (TaskStatus)
     ///
     /// # Return
@@ -554,9 +856,30 @@ impl TaskData {
         instruction_pointer: VirtualAddress,
         status: TaskStatus,
     ) -> Self {
+        TaskData::with_pid(
+            increment_pid(),
+            name,
+            cpu_flags,
+            stack_pointer,
+            instruction_pointer,
+            status,
+        )
+    }
+
+    /// Creates a new `TaskData` with a pid which was already taken by `increment_pid()`, e.g. for a
+    /// task of `NEW_TASKS`.
+    fn with_pid(
+        pid: usize,
+        name: char,
+        cpu_flags: u64,
+        stack_pointer: VirtualAddress,
+        instruction_pointer: VirtualAddress,
+        status: TaskStatus,
+    ) -> Self {
+        let stack_pointer = unsafe { context::init(stack_pointer, instruction_pointer, cpu_flags) };
         TaskData {
             name: name,
-            pid: increment_pid(),
+            pid,
             cpu_flags,
             stack_pointer,
             instruction_pointer,
@@ -565,59 +888,188 @@ impl TaskData {
             time_sleep: 1,
             time_active: 1,
             last_time_stamp: 1,
+            period: 0,
+            deadline: 0,
+            wcet: 0,
+            release_time: 0,
+            absolute_deadline: usize::MAX,
+            deadline_misses: 0,
+            job_missed: false,
+            skip_next_job: false,
+            overrun_policy: OverrunPolicy::Continue,
+            priority: DEFAULT_PRIORITY,
+            base_priority: DEFAULT_PRIORITY,
+            boosts: PriorityBoosts::new(),
+            preemption_level: DEFAULT_PRIORITY,
+            block_timeout: usize::MAX,
+            timed_out: false,
+            exit_code: 0,
+            stack: None,
+            heap_forbidden: false,
+            address_space: None,
+            kernel_stack: None,
         }
     }
 
-    /// Copies a `TaskData` and returns a new `TaskData` object.
-    pub fn copy(
+    /// Creates a new periodic `TaskData` with status `READY`.
+    ///
+    /// # Arguments
+    /// * `name` - (char) *Name* of the taks.
+    /// * `cpu_flags` - (u64) cpu flags.
+    /// * `stack_pointer` - (VirtualAddress) Top address of the stack.
+    /// * `instruction_pointer` - (VirtualAddress) The function which is executed by the task.
+    /// * `period` - (usize) Period of the task in tsc ticks.
+    /// * `deadline` - (usize) Deadline of each job relative to its release in tsc ticks.
+    /// * `wcet` - (usize) Worst case execution time of one job in tsc ticks.
+    ///
+    /// # Return
+    /// * TaskData - New created `TaskData`.
+    pub fn new_periodic(
         name: char,
-        pid: usize,
         cpu_flags: u64,
         stack_pointer: VirtualAddress,
         instruction_pointer: VirtualAddress,
-        status: TaskStatus,
-        sleep_ticks: usize,
-        time_sleep: usize,
-        time_active: usize,
-        last_time_stamp: usize,
+        period: usize,
+        deadline: usize,
+        wcet: usize,
     ) -> Self {
-        TaskData {
+        let mut task = TaskData::new(
             name,
-            pid,
             cpu_flags,
             stack_pointer,
             instruction_pointer,
-            status,
-            sleep_ticks,
-            time_sleep,
-            time_active,
-            last_time_stamp,
-        }
+            TaskStatus::READY,
+        );
+        task.period = period;
+        task.deadline = deadline;
+        task.wcet = wcet;
+        task
+    }
+
+    /// Releases a new job of the task. The task becomes `RUNNING` and the absolute deadline is
+    /// computed from the given release time.
+    ///
+    /// # Arguments
+    /// * `release_time` - (usize) Timestamp of the release.
+    pub fn release(&mut self, release_time: usize) {
+        self.status = TaskStatus::RUNNING;
+        self.job_missed = false;
+        self.release_time = release_time;
+        self.absolute_deadline = if self.deadline == 0 {
+            usize::MAX
+        } else {
+            release_time.saturating_add(self.deadline)
+        };
+    }
+
+    /// Sets the assigned priority of the task. Active boosts are kept.
+    ///
+    /// # Arguments
+    /// * `priority` - (u8) The new priority, at most `PRIORITY_LEVELS - 1`.
+    pub fn set_base_priority(&mut self, priority: u8) {
+        self.base_priority = priority;
+        self.update_priority();
+    }
+
+    /// Computes the priority from the base priority and the boosts of the held mutexes.
+    pub fn update_priority(&mut self) {
+        let boost = self.boosts.max();
+        self.priority = if boost > self.base_priority {
+            boost
+        } else {
+            self.base_priority
+        };
+    }
+
+    /// Returns `true` if the task can be scheduled (`READY` or `RUNNING`).
+    pub fn is_ready(&self) -> bool {
+        self.status == TaskStatus::READY || self.status == TaskStatus::RUNNING
     }
 }
 
+/// Adds a new task to `NEW_TASKS`, the main task starts it with a new stack. Periodic tasks are
+/// only added if the task set stays schedulable. The utilization of the task is checked against the
+/// bound of the scheduling policy (see `scheduler::admission`).
+///
+/// # Arguments
+/// * `name` - (char) *Name* of the task.
+/// * `entry` - (TaskEntry) The function which is executed by the task. Returning from it exits the
+/// task with the returned exit code.
+/// * `argument` - (usize) Argument for `entry`.
+/// * `stack_pages` - (usize) Number of pages of the stack.
+/// * `params` - (TaskParams) Timing parameters and priority.
+///
+/// # Return
+/// * `Result<TaskHandle, AdmissionError>` - Handle of the new task, `Err` if the task was
+/// rejected. The task is not started then.
+pub fn spawn(
+    name: char,
+    entry: TaskEntry,
+    argument: usize,
+    stack_pages: usize,
+    params: TaskParams,
+) -> Result<TaskHandle, AdmissionError> {
+    if params.priority as usize >= PRIORITY_LEVELS || stack_pages == 0 {
+        return Err(AdmissionError::InvalidParameters);
+    }
+    if params.period != 0 {
+        admission::admit(params.wcet, params.period, params.deadline)?;
+    }
+    let pid = increment_pid();
+    let handle = handle::register(pid);
+    let task = NewTask {
+        name,
+        pid,
+        entry,
+        argument,
+        stack_pages,
+        params,
+    };
+    without_interrupts(|| NEW_TASKS.lock().insert(0, task));
+    Ok(handle)
+}
+
 /// Clock which counts every second. The clock is starting by 00:00:00 on the top left corner.
 /// This function increments a variable by one each time and then calcutes the seconds / minutes / hours.
+/// The task has a period of one second and waits for its next period, so the clock does not drift.
+/// The text is written into a block of a pool, so the clock forbids the heap after its start.
 pub fn uptime1() {
     msleep(1000);
     trace_info!();
 
+    let pool = memory::BlockPool::new(8, 1);
+    let mut text = pool.alloc().expect("uptime1 pool empty");
+    forbid_heap();
+
     let mut r = 0;
     loop {
         r = r + 1;
         let color = Color::LightGreen;
-        let text = &format!(
-            "{:2}:{:2}:{:2}",
-            (r / (60 * 60)) % 24,
-            (r / (60)) % 60,
-            r % (60)
-        );
+        write_clock_field(&mut text[0..2], (r / (60 * 60)) % 24);
+        text[2] = b':';
+        write_clock_field(&mut text[3..5], (r / (60)) % 60);
+        text[5] = b':';
+        write_clock_field(&mut text[6..8], r % (60));
+        let text = str::from_utf8(&text).expect("uptime1 text invalid");
         vga_buffer::write_at_background(text, 0, 0, color, Color::Black);
-        trace_debug!("Uptime1 written {:?}", text);
-        msleep(1000);
+        wait_next_period();
     }
 }
 
+/// Writes a number of the clock right aligned like `{:2}`, without allocating.
+///
+/// # Arguments
+/// * `field` - (&mut [u8]) The two bytes of the field.
+/// * `value` - (usize) The number, less than 100.
+fn write_clock_field(field: &mut [u8], value: usize) {
+    field[0] = if value < 10 {
+        b' '
+    } else {
+        b'0' + (value / 10) as u8
+    };
+    field[1] = b'0' + (value % 10) as u8;
+}
+
 /// Similar to `uptime1()`, but on row 2.
 pub fn uptime2() {
     msleep(1000);
@@ -636,7 +1088,7 @@ pub fn uptime2() {
         );
         vga_buffer::write_at_background(text, 2, 0, color, Color::Black);
         trace_debug!("Uptime2 written {:?}", text);
-        msleep(1000);
+        wait_next_period();
     }
 }
 
@@ -658,7 +1110,7 @@ pub fn uptime3() {
         );
         vga_buffer::write_at_background(text, 4, 0, color, Color::Black);
         trace_debug!("Uptime3 written {:?}", text);
-        msleep(1000);
+        wait_next_period();
     }
 }
 
@@ -680,21 +1132,26 @@ pub fn uptime4() {
         );
         vga_buffer::write_at_background(text, 6, 0, color, Color::Black);
         trace_debug!("Uptime4 written {:?}", text);
-        msleep(1000);
+        wait_next_period();
     }
 }
 
-/// Used to add frequently new temporary clocks. Only use this function for testing. Otherwise the
-/// system will run out of heap due to frequently allocatin new heap.
+/// Used to add frequently new temporary clocks. Only use this function for testing. The stacks of
+/// the finished clocks are freed by the main task and reused.
 #[allow(dead_code)]
 pub fn add_new_temp_clocks() {
     msleep(2000);
     trace_info!();
     loop {
         msleep(1000);
-        NEW_TASKS
-            .lock()
-            .insert(0, VirtualAddress(uptime_temp as usize));
+        spawn(
+            'u',
+            uptime_temp,
+            0,
+            DEFAULT_STACK_PAGES,
+            TaskParams::aperiodic(DEFAULT_PRIORITY),
+        )
+        .expect("aperiodic tasks are always admitted");
         msleep(10000);
         trace_debug!("Added new temp task");
     }
@@ -702,7 +1159,7 @@ pub fn add_new_temp_clocks() {
 
 /// Temporary clock to show that it is possibly to start and stop tasks while the system is running.
 /// The clock counts - similar to the other clock tasks - but only up to 10.
-pub fn uptime_temp() {
+pub fn uptime_temp(_argument: usize) -> isize {
     msleep(1000);
     trace_info!();
     let mut r = 0;
@@ -722,11 +1179,235 @@ pub fn uptime_temp() {
         msleep(1000);
     }
     vga_buffer::write_at_background("          ", 10, 0, Color::Black, Color::Black);
-    finish_task();
+    0
+}
+
+/// Counter which runs as an isolated task, started by the shell. It counts up to 10 like
+/// `uptime_temp()`, but writes the text on its stack, because the heap is not mapped in its address
+/// space. The semaphore is used like any kernel object, only its lazy initialization needs the heap
+/// and is done with `kernel_call()`.
+pub fn isolated_counter(_argument: usize) -> isize {
+    kernel_call(|| lazy_static::initialize(&ISOLATED_COUNTER));
+    ISOLATED_COUNTER.wait();
+    let mut text = [b' '; 8];
+    for r in 1..11 {
+        write_clock_field(&mut text[6..8], r);
+        let text = str::from_utf8(&text).expect("isolated counter text invalid");
+        vga_buffer::write_at_background(text, 12, 0, Color::LightBlue, Color::Black);
+        msleep(1000);
+    }
+    vga_buffer::write_at_background("        ", 12, 0, Color::Black, Color::Black);
+    ISOLATED_COUNTER.signal();
+    0
+}
+
+/// Callback of the timers of `timer_demo()`. The argument is the event which is set.
+fn timer_event(event: usize) {
+    TIMER_EVENTS.set_from_isr(event);
 }
 
-/// Task of the tetris game.
-pub fn tetris() {
+/// Blinks a marker with software timers, started by the shell command *timer*. An auto-reload
+/// timer toggles the marker every 250ms, after 3s a one-shot timer slows it down to one toggle per
+/// second. The task only waits for the events of the timer callbacks and ends after 20 toggles.
+/// Timers left by a killed demo are deleted first, its lock is released when it is killed.
+pub fn timer_demo(_argument: usize) -> isize {
+    // the callbacks run in the interrupt, where the lazy initialization must not allocate
+    lazy_static::initialize(&TIMER_EVENTS);
+    let mut timers = match TIMER_DEMO.try_lock() {
+        Some(timers) => timers,
+        None => return 1,
+    };
+    if let Some((tick, slow_down)) = timers.take() {
+        timer::delete(tick);
+        timer::delete(slow_down);
+    }
+    TIMER_EVENTS.clear(TIMER_TICK | TIMER_SLOW_DOWN);
+    let tick = timer::create(timer_event, TIMER_TICK, 250, TimerMode::AutoReload);
+    let slow_down = timer::create(timer_event, TIMER_SLOW_DOWN, 3000, TimerMode::OneShot);
+    let (tick, slow_down) = match (tick, slow_down) {
+        (Some(tick), Some(slow_down)) => (tick, slow_down),
+        (tick, slow_down) => {
+            // all timers are used, the created one is freed again
+            for handle in tick.iter().chain(slow_down.iter()) {
+                timer::delete(*handle);
+            }
+            return 2;
+        }
+    };
+    *timers = Some((tick, slow_down));
+    timer::start(tick);
+    timer::start(slow_down);
+
+    let mut visible = false;
+    let mut toggles = 0;
+    while toggles < 20 {
+        let events = TIMER_EVENTS.wait(TIMER_TICK | TIMER_SLOW_DOWN, WaitMode::Any, true);
+        if events & TIMER_SLOW_DOWN != 0 {
+            // the new period is used from the restart on
+            timer::change_period(tick, 1000);
+            timer::reset(tick);
+        }
+        if events & TIMER_TICK != 0 {
+            visible = !visible;
+            let color = if visible { Color::Yellow } else { Color::Black };
+            vga_buffer::write_at_background("*", 14, 0, color, Color::Black);
+            toggles += 1;
+        }
+    }
+    timer::stop(tick);
+    assert!(!timer::is_active(slow_down), "one-shot timer still active");
+    timer::delete(tick);
+    timer::delete(slow_down);
+    *timers = None;
+    vga_buffer::write_at_background(" ", 14, 0, Color::Black, Color::Black);
+    0
+}
+
+/// Shows the overrun policies, started by the shell command *overrun*. A periodic task misses the
+/// deadline of every job and uses another policy for each job, a handler task reports the miss
+/// which wakes it up. The demo ends when the periodic task is killed by its last miss.
+pub fn overrun_demo(_argument: usize) -> isize {
+    let handler = spawn(
+        'm',
+        miss_handler,
+        0,
+        DEFAULT_STACK_PAGES,
+        TaskParams::aperiodic(DEFAULT_PRIORITY),
+    )
+    .expect("aperiodic tasks are always admitted");
+    let params = TaskParams::periodic(ms_to_ticks(1000), ms_to_ticks(20), ms_to_ticks(5), 2);
+    let pid = handler.pid();
+    let code = match spawn('o', overrun_task, pid, DEFAULT_STACK_PAGES, params) {
+        Ok(overrun) => overrun.join(),
+        Err(_) => 1,
+    };
+    handler.kill();
+    handler.join();
+    vga_buffer::write_at_background("                      ", 16, 0, Color::Black, Color::Black);
+    if code == KILLED {
+        0
+    } else {
+        1
+    }
+}
+
+/// Periodic task of `overrun_demo()`, which runs longer than its deadline in every job. The first
+/// miss is only counted, the second skips the next job, the third wakes up the handler task and
+/// the fourth kills the task.
+///
+/// # Arguments
+/// * `handler` - (usize) Pid of the handler task.
+fn overrun_task(handler: usize) -> isize {
+    let policies = [
+        OverrunPolicy::Continue,
+        OverrunPolicy::SkipNextJob,
+        OverrunPolicy::Handler(handler),
+        OverrunPolicy::Kill,
+    ];
+    for policy in policies.iter() {
+        set_overrun_policy(*policy);
+        // the deadline is 20ms
+        active_sleep(50);
+        wait_next_period();
+    }
+    0
+}
+
+/// Handler task of `overrun_demo()`. It sleeps until a task with `OverrunPolicy::Handler` misses
+/// its deadline and prints the miss. The task is killed by the demo.
+fn miss_handler(_argument: usize) -> isize {
+    loop {
+        sleep_until(usize::MAX);
+        if let Some(miss) = deadline::last_miss() {
+            let text = format!("Handler: task {} missed", miss.name);
+            vga_buffer::write_at_background(&text, 16, 0, Color::LightRed, Color::Black);
+        }
+    }
+}
+
+/// Shows the protocols against priority inversion, started by the shell command *inversion*. For
+/// each protocol a low priority task holds a mutex for one second, then a high priority task waits
+/// for it and a medium priority task runs. The boost of the low task keeps the medium task from
+/// delaying the high task, which prints how long it waited. The priorities are only used by the
+/// fixed priority policy.
+pub fn inversion_demo(_argument: usize) -> isize {
+    let names = ["Inheritance", "Ceiling"];
+    let all = INVERSION_LOCKED | INVERSION_LOW_DONE | INVERSION_MEDIUM_DONE;
+    for (index, name) in names.iter().enumerate() {
+        INVERSION_EVENTS.clear(all);
+        let start = |task_name: char, entry: TaskEntry, priority: u8| {
+            spawn(
+                task_name,
+                entry,
+                index,
+                DEFAULT_STACK_PAGES,
+                TaskParams::aperiodic(priority),
+            )
+            .expect("aperiodic tasks are always admitted")
+        };
+        // the handles of the low and medium task are dropped, their events are used instead
+        start('L', inversion_low, DEFAULT_PRIORITY);
+        let locked = INVERSION_EVENTS.wait_timeout(INVERSION_LOCKED, WaitMode::Any, true, 1000);
+        if locked.is_none() {
+            return 1;
+        }
+        let high = start('H', inversion_high, INVERSION_CEILING);
+        start('M', inversion_medium, INVERSION_CEILING - 1);
+        let waited = high.join();
+        INVERSION_EVENTS.wait(
+            INVERSION_LOW_DONE | INVERSION_MEDIUM_DONE,
+            WaitMode::All,
+            true,
+        );
+        let text = format!("{}: {} ms", name, waited);
+        vga_buffer::write_at_background(&text, 17 + index as u8, 0, Color::White, Color::Black);
+    }
+    msleep(5000);
+    for row in 17..19 {
+        vga_buffer::write_at_background("                   ", row, 0, Color::Black, Color::Black);
+    }
+    0
+}
+
+/// Low priority task of `inversion_demo()`, holds the mutex for one second.
+///
+/// # Arguments
+/// * `index` - (usize) Index of the mutex in `INVERSION_MUTEXES`.
+fn inversion_low(index: usize) -> isize {
+    {
+        let mut count = INVERSION_MUTEXES[index].lock();
+        INVERSION_EVENTS.set(INVERSION_LOCKED);
+        active_sleep(1000);
+        *count += 1;
+    }
+    INVERSION_EVENTS.set(INVERSION_LOW_DONE);
+    0
+}
+
+/// Medium priority task of `inversion_demo()`, runs for one second without the mutex.
+fn inversion_medium(_argument: usize) -> isize {
+    active_sleep(1000);
+    INVERSION_EVENTS.set(INVERSION_MEDIUM_DONE);
+    0
+}
+
+/// High priority task of `inversion_demo()`, waits for the mutex.
+///
+/// # Arguments
+/// * `index` - (usize) Index of the mutex in `INVERSION_MUTEXES`.
+///
+/// # Return
+/// * `isize` - The time the task waited for the mutex in milliseconds.
+fn inversion_high(index: usize) -> isize {
+    let start = rdtsc() as usize;
+    let mut count = INVERSION_MUTEXES[index].lock();
+    let waited = (rdtsc() as usize - start) / ms_to_ticks(1);
+    *count += 1;
+    waited as isize
+}
+
+/// Task of the tetris game. The task is killed if the shell terminates it.
+pub fn tetris(_argument: usize) -> isize {
     msleep(1000);
     trace_info!();
     let mut gameover = false;
@@ -742,7 +1423,7 @@ pub fn tetris() {
         if SHELL.lock().terminate_running_task == true {
             SHELL.lock().reset_shell();
             // end the task
-            finish_task();
+            exit(KILLED);
         }
 
         PIECE.lock().print_piece();
@@ -757,40 +1438,48 @@ pub fn tetris() {
     }
     msleep(2000);
     SHELL.lock().reset_shell();
-    finish_task();
+    0
 }
 
 /// Task of the shell.
+/// Waits for key events of the keyboard task (see `KEY_EVENTS`) and passes them to the shell. If
+/// no key is pressed, the cursor blinks.
 pub fn shell() {
     msleep(1500);
     SHELL.lock().init_shell();
+    let mut cursor_visible = false;
     loop {
-        unsafe {
-            if TASK_STARTED != true {
-                SHELL.lock().cursor_on();
-                msleep(500);
-                SHELL.lock().cursor_off();
-                msleep(500);
-            } else {
-                msleep(100);
-            }
+        if let Some(key) = KEY_EVENTS.receive_timeout(CURSOR_BLINK_INTERVAL) {
+            SHELL.lock().parse_input(key);
+            continue;
+        }
+        if unsafe { TASK_STARTED } && !cursor_visible {
+            continue;
+        }
+        cursor_visible = !cursor_visible;
+        if cursor_visible {
+            SHELL.lock().cursor_on();
+        } else {
+            SHELL.lock().cursor_off();
         }
     }
-    //    finish_task();
+    //    exit(0);
 }
 
 /// Task of htop.
 /// Prints out all the active tasks and computes their utilization.
-/// The utilization results from the active time divided by the active + passive time.
+/// The utilization results from the active time divided by the active + passive time. Below the
+/// tasks the utilization which is reserved by the admission control is shown.
 /// The process is looping permanently while the processes are calculated and printed. There are no interrupts allowed to avoid concurrency problems
 pub fn htop() {
     trace_info!();
     loop {
-        msleep(1000);
+        wait_next_period();
         unsafe {
             x86_64::instructions::interrupts::disable();
         }
-        for (i, task) in TASKS.lock().iter().enumerate() {
+        let tasks = TASKS.lock();
+        for (i, task) in tasks.iter().enumerate() {
             let percent_digits = calc_float_percent_from_int(
                 task.time_active,
                 task.time_active + task.time_sleep,
@@ -814,14 +1503,26 @@ pub fn htop() {
             );
             vga_buffer::write_at_background(&name, i as u8, 15, Color::Red, Color::Black);
         }
+        // utilization which the admission control reserved for the periodic tasks, in ppm
+        let reserved = admission::total_utilization();
+        let text = format!("Reserved: {}.{}%", reserved / 10_000, reserved / 1_000 % 10);
+        vga_buffer::write_at_background(
+            "                    ",
+            tasks.len() as u8 + 1,
+            15,
+            Color::Black,
+            Color::Black,
+        );
+        vga_buffer::write_at_background(&text, tasks.len() as u8, 15, Color::Red, Color::Black);
+        drop(tasks);
 
         unsafe {
             x86_64::instructions::interrupts::enable();
         }
     }
 
-    // End the task, finish_task is unreachable
-    // finish_task();
+    // End the task, exit is unreachable
+    // exit(0);
 }
 
 /// Calculates the digits of the percentage ratio of two usize numbers, of which a float can be
@@ -855,7 +1556,8 @@ fn calc_float_percent_from_int(num: usize, denom: usize, digits: usize) -> Vec<u
 
 /// Moved keyboard handler from interrupts to own function
 /// keyboard handler as interrupt causes to PIC's deadlock problems.
-/// for now the function polls every 50ms.
+/// for now the function polls every 20ms. The keys are sent to the shell task with the message
+/// queue `KEY_EVENTS`. If the queue is full, the key is dropped.
 ///
 /// https://wiki.osdev.org/PS/2_Keyboard
 ///
@@ -874,11 +1576,13 @@ pub fn task_keyboard() {
                     // if bit 5 is set -> mouse event
                     let scan_code = port::inb(0x60);
                     if let Some(c) = keyboard::from_scancode(scan_code as usize) {
-                        SHELL.lock().parse_input(c);
+                        if KEY_EVENTS.try_send(c).is_err() {
+                            trace_warn!("key event queue full");
+                        }
                     }
                 }
             }
-            msleep(20);
+            wait_next_period();
         }
     }
 }
@@ -911,12 +1615,125 @@ fn increment_highscore() -> usize {
     }
 }
 
-/// Always called at the end of a task. The running task is marked as finished. After that the
-/// scheduler is called by a timer interrupt.
-fn finish_task() {
-    trace_info!("TASK FINISHED");
-    unsafe {
-        RUNNING_TASK.lock().status = TaskStatus::FINISHED;
-        int!(0x20);
+/// Maximum stack depth of a task, measured with the painted stack (see `Stack::high_water_mark()`).
+#[derive(Debug, Clone, Copy)]
+pub struct StackUsage {
+    /// *Name* of the task.
+    pub name: char,
+    /// Pid of the task.
+    pub pid: usize,
+    /// Maximum number of used bytes.
+    pub used: usize,
+    /// Size of the stack in bytes.
+    pub size: usize,
+}
+
+/// Returns the maximum stack depth of all tasks, including the running task. The main task is
+/// not included, its stack was not allocated by the stack allocator.
+///
+/// # Return
+/// * `Vec<StackUsage>` - The stack usage of the tasks, ordered by pid.
+pub fn stack_usage() -> Vec<StackUsage> {
+    // the stacks are measured with disabled interrupts, so they can not be freed meanwhile
+    let mut usage: Vec<StackUsage> = without_interrupts(|| {
+        let tasks = TASKS.lock();
+        let running = unsafe { RUNNING_TASK.lock() };
+        tasks
+            .iter()
+            .chain(Some(&*running).into_iter())
+            .filter_map(|task| {
+                task.stack.as_ref().map(|stack| StackUsage {
+                    name: task.name,
+                    pid: task.pid,
+                    used: stack.high_water_mark(),
+                    size: stack.top() - stack.bottom(),
+                })
+            })
+            .collect()
+    });
+    usage.sort_by_key(|entry| entry.pid);
+    usage
+}
+
+/// Sets the `OverrunPolicy` of the running task, which is applied when the task misses a deadline.
+///
+/// # Arguments
+/// * `policy` - (OverrunPolicy) The new policy.
+pub fn set_overrun_policy(policy: OverrunPolicy) {
+    without_interrupts(|| unsafe { RUNNING_TASK.lock().overrun_policy = policy });
+}
+
+/// Forbids heap allocations for the rest of the running task, because their time depends on the
+/// fragmentation of the heap. Real-time tasks call it after their initialization and use a
+/// `memory::BlockPool` instead. An allocation afterwards causes a panic. Freeing memory is still
+/// allowed.
+pub fn forbid_heap() {
+    without_interrupts(|| {
+        unsafe { RUNNING_TASK.lock().heap_forbidden = true };
+        memory::set_heap_forbidden(true);
+    });
+}
+
+/// Calls a kernel function from an isolated task, which would fault on the heap or the kernel data
+/// in it. Like a syscall, the function runs in the kernel address space with the heap allowed,
+/// afterwards the address space and the heap flag of the task are restored. Other tasks call the
+/// function directly, so tasks which forbid the heap still can't allocate.
+///
+/// # Arguments
+/// * `function` - (FnOnce() -> R) The kernel function.
+///
+/// # Return
+/// * `R` - Result of `function`.
+pub fn kernel_call<R, F: FnOnce() -> R>(function: F) -> R {
+    let (isolated, heap_forbidden) = without_interrupts(|| unsafe {
+        let running = RUNNING_TASK.lock();
+        (running.address_space.is_some(), running.heap_forbidden)
+    });
+    if !isolated {
+        return function();
     }
+    // the scheduler saves cr3 and the heap flag, so the task may be preempted in between
+    let task_space = control_regs::cr3();
+    without_interrupts(|| {
+        memory::enter_kernel_space();
+        unsafe { RUNNING_TASK.lock().heap_forbidden = false };
+        memory::set_heap_forbidden(false);
+    });
+    let result = function();
+    without_interrupts(|| {
+        unsafe { RUNNING_TASK.lock().heap_forbidden = heap_forbidden };
+        memory::set_heap_forbidden(heap_forbidden);
+        unsafe { control_regs::cr3_write(task_space) };
+    });
+    result
+}
+
+/// Ends the running task. The running task is marked as finished and the scheduler is called. The
+/// exit code is passed to the `TaskHandle` of the task when the scheduler removes it.
+///
+/// # Arguments
+/// * `code` - (isize) Exit code of the task.
+pub fn exit(code: isize) -> ! {
+    // the task may have forbidden the heap or be isolated, but the exit needs the heap
+    memory::enter_kernel_space();
+    memory::set_heap_forbidden(false);
+    trace_info!("TASK FINISHED");
+    without_interrupts(|| unsafe {
+        let mut running = RUNNING_TASK.lock();
+        running.exit_code = code;
+        running.status = TaskStatus::FINISHED;
+    });
+    scheduler::reschedule();
+    unreachable!("finished task was scheduled");
+}
+
+/// First function of a task started with `spawn()`. The arguments are passed in the registers of
+/// the initial context (see `context::set_arguments()`). Returning from `entry` exits the task.
+///
+/// # Arguments
+/// * `entry` - (TaskEntry) The function of the task.
+/// * `argument` - (usize) Argument for `entry`.
+extern "C" fn task_start(entry: TaskEntry, argument: usize) -> ! {
+    let code = entry(argument);
+    exit(code);
 }
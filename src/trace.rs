@@ -5,8 +5,8 @@
 //! For easier usage there are different macros for each trace level.
 //! There is also a macro to change the trace level while the system is running.
 use cpuio::UnsafePort;
+use features::without_interrupts;
 use spin::Mutex;
-use x86_64;
 use x86_64::instructions::rdtsc;
 
 /// The serial port to write is fix, so there is no need to store any data in the struct.
@@ -70,18 +70,15 @@ impl Trace {
     }
 }
 
-/// Writes a trace with disabled interrupts.
+/// Writes a trace with disabled interrupts. The interrupts are only enabled again if they were
+/// enabled before, so traces can also be written inside interrupt handlers.
 ///
 /// # Arguments
 /// * `level` - (&str) Trace level ('Info' in the example).
 /// * `fn_name` - (&str) Function name ('module:function_name' in the  example)
 /// * `info_text` - (&str) Additional info ('Some additional info text' in the example).
 pub fn trace_info(level: &str, fn_name: &str, info_text: &str) {
-    unsafe {
-        x86_64::instructions::interrupts::disable();
-        trace_info_without_interrupts(level, fn_name, info_text);
-        x86_64::instructions::interrupts::enable();
-    }
+    without_interrupts(|| trace_info_without_interrupts(level, fn_name, info_text));
 }
 
 /// Writes a trace with given arguments.